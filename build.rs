@@ -0,0 +1,119 @@
+extern crate protobuf_codegen_pure;
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PROTO: &str = "proto/ds_srv.proto";
+
+/// Resolve the commit we are being built from, preferring an explicit value
+/// from the environment (Docker builds usually do not ship `.git`).
+fn git_commit() -> String {
+    if let Ok(commit) = env::var("DS_SRV_GIT_COMMIT") {
+        return commit;
+    }
+
+    match Command::new("git").args(&["rev-parse", "HEAD"]).output() {
+        Ok(ref out) if out.status.success() => {
+            String::from_utf8_lossy(&out.stdout).trim().to_string()
+        }
+        _ => "unknown".to_string(),
+    }
+}
+
+/// Files that change when the checked out commit does: `.git/HEAD`, the
+/// branch it points to and `.git/packed-refs`, where `git gc` moves branches.
+/// Missing files are left out, as cargo would rebuild on every run otherwise.
+fn git_head_files(manifest_dir: &str) -> Vec<String> {
+    let git = Path::new(manifest_dir).join(".git");
+    let mut head = String::new();
+    if File::open(git.join("HEAD"))
+        .and_then(|mut f| f.read_to_string(&mut head))
+        .is_err()
+    {
+        return Vec::new();
+    }
+
+    let mut files = vec![".git/HEAD".to_string(), ".git/packed-refs".to_string()];
+    let head = head.trim();
+    if head.starts_with("ref: ") {
+        files.push(format!(".git/{}", head.trim_start_matches("ref: ")));
+    }
+    files.retain(|file| Path::new(manifest_dir).join(file).exists());
+    files
+}
+
+/// Look up the resolved version of the `deepspeech` crate in `Cargo.lock`.
+fn deepspeech_crate_version(manifest_dir: &str) -> String {
+    let mut lock = String::new();
+    let lock_path = Path::new(manifest_dir).join("Cargo.lock");
+    if let Ok(mut file) = File::open(lock_path) {
+        let _ = file.read_to_string(&mut lock);
+    }
+
+    let mut lines = lock.lines();
+    while let Some(line) = lines.next() {
+        if line.trim() == "name = \"deepspeech\"" {
+            if let Some(version) = lines.next() {
+                return version
+                    .trim()
+                    .trim_start_matches("version = ")
+                    .trim_matches('"')
+                    .to_string();
+            }
+        }
+    }
+
+    "unknown".to_string()
+}
+
+/// Generate the gRPC messages of `PROTO` into `out_dir`.
+fn generate_protobuf(out_dir: &str) {
+    protobuf_codegen_pure::run(protobuf_codegen_pure::Args {
+        out_dir: out_dir,
+        includes: &["proto"],
+        input: &[PROTO],
+        customize: Default::default(),
+    }).expect("Unable to generate protobuf code");
+
+    // `include!` rejects the inner attributes and doc comments generated
+    // files start with
+    let path = Path::new(out_dir).join("ds_srv.rs");
+    let mut code = String::new();
+    File::open(&path)
+        .and_then(|mut f| f.read_to_string(&mut code))
+        .expect("Unable to read generated code");
+    let code: Vec<&str> = code
+        .lines()
+        .filter(|l| !l.starts_with("#![") && !l.starts_with("//!"))
+        .collect();
+    File::create(&path)
+        .and_then(|mut f| f.write_all(code.join("\n").as_bytes()))
+        .expect("Unable to write generated code");
+}
+
+fn main() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let build_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    println!("cargo:rustc-env=DS_SRV_GIT_COMMIT={}", git_commit());
+    println!("cargo:rustc-env=DS_SRV_BUILD_TIMESTAMP={}", build_timestamp);
+    println!(
+        "cargo:rustc-env=DS_SRV_DEEPSPEECH_CRATE_VERSION={}",
+        deepspeech_crate_version(&manifest_dir)
+    );
+    println!("cargo:rerun-if-env-changed=DS_SRV_GIT_COMMIT");
+    for file in git_head_files(&manifest_dir) {
+        println!("cargo:rerun-if-changed={}", file);
+    }
+    println!("cargo:rerun-if-changed=Cargo.lock");
+
+    generate_protobuf(&env::var("OUT_DIR").unwrap());
+    println!("cargo:rerun-if-changed={}", PROTO);
+}
@@ -0,0 +1,236 @@
+extern crate chrono;
+extern crate serde_json;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum AccessLogFormat {
+    Combined,
+    Json,
+}
+
+#[derive(Debug, Clone, Default)]
+/// Per-request details only known to the route handling the request
+pub struct AccessStats {
+    pub bytes_in: u64,
+    pub api_key: Option<String>,
+    pub audio_duration: Option<Duration>,
+    pub queue_wait: Option<Duration>,
+    pub inference_time: Option<Duration>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccessEntry {
+    pub timestamp: String,
+    pub client: Option<String>,
+    pub request_id: String,
+    pub api_key: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    pub status: u16,
+    pub bytes_in: u64,
+    pub bytes_out: Option<u64>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub duration_ms: f64,
+    pub audio_duration_ms: Option<f64>,
+    pub queue_wait_ms: Option<f64>,
+    pub inference_ms: Option<f64>,
+    pub real_time_factor: Option<f64>,
+}
+
+pub fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + f64::from(d.subsec_nanos()) / 1_000_000.0
+}
+
+impl AccessEntry {
+    pub fn new(client: Option<SocketAddr>, request_id: &str) -> AccessEntry {
+        AccessEntry {
+            timestamp: chrono::Local::now().format("%d/%b/%Y:%H:%M:%S %z").to_string(),
+            client: client.map(|c| c.ip().to_string()),
+            request_id: request_id.to_string(),
+            api_key: None,
+            method: String::new(),
+            path: String::new(),
+            version: String::new(),
+            status: 0,
+            bytes_in: 0,
+            bytes_out: None,
+            referer: None,
+            user_agent: None,
+            duration_ms: 0.0,
+            audio_duration_ms: None,
+            queue_wait_ms: None,
+            inference_ms: None,
+            real_time_factor: None,
+        }
+    }
+
+    pub fn set_stats(&mut self, stats: &AccessStats) {
+        self.bytes_in = stats.bytes_in;
+        self.api_key = stats.api_key.clone();
+        self.audio_duration_ms = stats.audio_duration.map(millis);
+        self.queue_wait_ms = stats.queue_wait.map(millis);
+        self.inference_ms = stats.inference_time.map(millis);
+        self.real_time_factor = match (self.inference_ms, self.audio_duration_ms) {
+            (Some(inference), Some(audio)) if audio > 0.0 => Some(inference / audio),
+            _ => None,
+        };
+    }
+
+    fn or_dash<T: ToString>(v: &Option<T>) -> String {
+        match *v {
+            Some(ref v) => v.to_string(),
+            None => "-".to_string(),
+        }
+    }
+
+    /// Apache Combined Log Format, followed by our own fields.
+    pub fn to_combined(&self) -> String {
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" request_id={} api_key={} bytes_in={} duration_ms={:.3} audio_ms={} queue_wait_ms={} inference_ms={} rtf={}",
+            AccessEntry::or_dash(&self.client),
+            self.timestamp,
+            self.method,
+            self.path,
+            self.version,
+            self.status,
+            AccessEntry::or_dash(&self.bytes_out),
+            AccessEntry::or_dash(&self.referer),
+            AccessEntry::or_dash(&self.user_agent),
+            self.request_id,
+            AccessEntry::or_dash(&self.api_key),
+            self.bytes_in,
+            self.duration_ms,
+            AccessEntry::or_dash(&self.audio_duration_ms),
+            AccessEntry::or_dash(&self.queue_wait_ms),
+            AccessEntry::or_dash(&self.inference_ms),
+            AccessEntry::or_dash(&self.real_time_factor),
+        )
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// A log file rotated to `<path>.1` ... `<path>.<max_files>` once it grows
+/// past `max_size` bytes.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: u32,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, max_files: u32) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file: file,
+            size: size,
+            max_size: max_size,
+            max_files: max_files,
+        })
+    }
+
+    fn rotated(&self, index: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for i in (1..self.max_files).rev() {
+                let from = self.rotated(i);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(i + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.max_size > 0 && self.size + line.len() as u64 + 1 > self.max_size && self.size > 0 {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    file: Mutex<RotatingFile>,
+}
+
+impl AccessLog {
+    pub fn open(
+        path: &str,
+        format: AccessLogFormat,
+        max_size: u64,
+        max_files: u32,
+    ) -> io::Result<AccessLog> {
+        Ok(AccessLog {
+            format: format,
+            file: Mutex::new(RotatingFile::open(Path::new(path), max_size, max_files)?),
+        })
+    }
+
+    pub fn log(&self, entry: &AccessEntry) {
+        let line = match self.format {
+            AccessLogFormat::Combined => entry.to_combined(),
+            AccessLogFormat::Json => entry.to_json(),
+        };
+
+        match self.file.lock() {
+            Ok(mut file) => {
+                if let Err(err) = file.write_line(&line) {
+                    error!("Unable to write access log: {:?}", err);
+                }
+            }
+            Err(err) => error!("Access log lock poisoned: {:?}", err),
+        }
+    }
+}
+
+#[test]
+fn test_access_log_rotation() {
+    let dir = ::std::env::temp_dir().join(format!("ds-srv-access-log-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("access.log");
+
+    let log = AccessLog::open(path.to_str().unwrap(), AccessLogFormat::Json, 1000, 2).unwrap();
+    let entry = AccessEntry::new(None, "test");
+    for _ in 0..10 {
+        log.log(&entry);
+    }
+
+    assert!(path.exists());
+    assert!(dir.join("access.log.1").exists());
+    assert!(dir.join("access.log.2").exists());
+    assert!(!dir.join("access.log.3").exists());
+    assert!(fs::metadata(&path).unwrap().len() <= 1000);
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,90 @@
+#[derive(Debug, Clone, PartialEq)]
+/// One step turning a reference into a hypothesis
+pub enum Edit<T> {
+    Same(T),
+    Substitute(T, T),
+    Delete(T),
+    Insert(T),
+}
+
+impl<T> Edit<T> {
+    pub fn is_error(&self) -> bool {
+        !matches!(*self, Edit::Same(_))
+    }
+}
+
+/// The fewest edits (Levenshtein) turning `reference` into `hypothesis`.
+pub fn align<T: PartialEq + Clone>(reference: &[T], hypothesis: &[T]) -> Vec<Edit<T>> {
+    let (n, m) = (reference.len(), hypothesis.len());
+    // cost[i][j]: edits between the first i reference and j hypothesis items
+    let mut cost = vec![vec![0usize; m + 1]; n + 1];
+    for (i, row) in cost.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in cost[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=n {
+        for j in 1..=m {
+            let substitution = if reference[i - 1] == hypothesis[j - 1] { 0 } else { 1 };
+            cost[i][j] = (cost[i - 1][j - 1] + substitution)
+                .min(cost[i - 1][j] + 1)
+                .min(cost[i][j - 1] + 1);
+        }
+    }
+
+    let mut edits = Vec::new();
+    let (mut i, mut j) = (n, m);
+    while i > 0 || j > 0 {
+        // Prefer keeping words, then insertions and deletions over
+        // substitutions, for diffs that read naturally
+        let same = i > 0 && j > 0 && reference[i - 1] == hypothesis[j - 1];
+        if same && cost[i][j] == cost[i - 1][j - 1] {
+            edits.push(Edit::Same(reference[i - 1].clone()));
+            i -= 1;
+            j -= 1;
+        } else if j > 0 && cost[i][j] == cost[i][j - 1] + 1 {
+            edits.push(Edit::Insert(hypothesis[j - 1].clone()));
+            j -= 1;
+        } else if i > 0 && cost[i][j] == cost[i - 1][j] + 1 {
+            edits.push(Edit::Delete(reference[i - 1].clone()));
+            i -= 1;
+        } else {
+            edits.push(Edit::Substitute(reference[i - 1].clone(), hypothesis[j - 1].clone()));
+            i -= 1;
+            j -= 1;
+        }
+    }
+
+    edits.reverse();
+    edits
+}
+
+/// Word edits as text, `[-removed-]` and `{+added+}` marking changes.
+pub fn render_diff(edits: &[Edit<&str>]) -> String {
+    edits
+        .iter()
+        .map(|edit| match *edit {
+            Edit::Same(word) => word.to_string(),
+            Edit::Substitute(old, new) => format!("[-{}-]{{+{}+}}", old, new),
+            Edit::Delete(old) => format!("[-{}-]", old),
+            Edit::Insert(new) => format!("{{+{}+}}", new),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[test]
+fn test_align() {
+    let reference: Vec<&str> = "the cat sat on the mat".split_whitespace().collect();
+    let hypothesis: Vec<&str> = "the bat sat on mat today".split_whitespace().collect();
+    let edits = align(&reference, &hypothesis);
+
+    assert_eq!(edits.iter().filter(|e| e.is_error()).count(), 3);
+    assert_eq!(
+        render_diff(&edits),
+        "the [-cat-]{+bat+} sat on [-the-] mat {+today+}"
+    );
+    assert_eq!(align::<&str>(&[], &["a"]), vec![Edit::Insert("a")]);
+    assert!(align(&reference, &reference).iter().all(|e| !e.is_error()));
+}
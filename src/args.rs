@@ -1,8 +1,27 @@
 extern crate clap;
-extern crate simplelog;
+extern crate log;
 
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use access_log::AccessLogFormat;
+use bench::BenchOptions;
+use config::{env_name, ConfigError, ConfigValues};
+use cors::{split_list, CorsConfig};
+use evaluate::{parse_grid, EvaluateOptions};
+use debug_dump::{parse_mode, DumpMode, Retention};
+use http::{format_by_name, ResponseFormat};
+use logging::LogFormat;
+use offline::OfflineOptions;
+use replay::ReplayOptions;
+use subtitles::SubtitleOptions;
+use listen::{self, ListenAddr, RouteSet};
+use tls::TlsOptions;
+use unix_socket::{self, UnixSocketOptions};
+
+use std::env;
+use std::ffi::OsString;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
 use std::str::FromStr;
+use std::time::Duration;
 
 pub type TcpPort = u16;
 
@@ -14,56 +33,307 @@ pub enum VerbosityLevel {
     ERROR = 3,
 }
 
-impl Into<simplelog::LevelFilter> for VerbosityLevel {
-    fn into(self) -> simplelog::LevelFilter {
+impl Into<log::LevelFilter> for VerbosityLevel {
+    fn into(self) -> log::LevelFilter {
         match self {
-            VerbosityLevel::DEBUG => simplelog::LevelFilter::Debug,
-            VerbosityLevel::INFO => simplelog::LevelFilter::Info,
-            VerbosityLevel::WARN => simplelog::LevelFilter::Warn,
-            VerbosityLevel::ERROR => simplelog::LevelFilter::Error,
+            VerbosityLevel::DEBUG => log::LevelFilter::Debug,
+            VerbosityLevel::INFO => log::LevelFilter::Info,
+            VerbosityLevel::WARN => log::LevelFilter::Warn,
+            VerbosityLevel::ERROR => log::LevelFilter::Error,
         }
     }
 }
 
+/// Settings accepted from the configuration file, `DS_SRV_*` environment
+/// variables and command-line flags, in increasing order of precedence
+pub const CONFIG_KEYS: &[&str] = &[
+    "http_ip",
+    "http_port",
+    "http_listen",
+    "http_ipv6_only",
+    "http_tcp",
+    "http_socket",
+    "http_socket_mode",
+    "grpc_listen",
+    "grpc_max_streams",
+    "dump_dir",
+    "dump_mode",
+    "dump_max_files",
+    "dump_max_age",
+    "dump_max_size",
+    "warmup_dir",
+    "warmup_cycles",
+    "warmup_budget",
+    "warmup_synthetic_duration",
+    "model",
+    "scorer",
+    "version_file",
+    "shutdown_grace_period",
+    "verbosity",
+    "log_format",
+    "access_log",
+    "access_log_format",
+    "access_log_max_size",
+    "access_log_max_files",
+    "tls_cert",
+    "tls_key",
+    "tls_client_ca",
+    "auth_file",
+    "max_body_size",
+    "max_audio_duration",
+    "rate_limit",
+    "rate_limit_burst",
+    "cors_allowed_origins",
+    "cors_allowed_methods",
+    "cors_allowed_headers",
+    "cors_max_age",
+    "subtitle_max_line_length",
+    "subtitle_max_cue_duration",
+    "postprocess",
+    "replacements_file",
+    "profanity_file",
+    "redaction_patterns_file",
+    "cache_size",
+    "cache_dir",
+    "cache_dir_max_entries",
+];
+
 #[derive(Debug, Clone)]
 /// Holds the program's runtime configuration
 pub struct RuntimeConfig {
     pub http_ip: IpAddr,
     pub http_port: TcpPort,
+    /// Addresses to serve HTTP on instead of `http_ip`:`http_port`
+    pub http_listen: Vec<ListenAddr>,
+    pub http_ipv6_only: bool,
+    /// Whether to serve HTTP over TCP, or only on the socket
+    pub http_tcp: bool,
+    pub unix_socket: Option<UnixSocketOptions>,
+    /// Where to serve the gRPC API, if at all
+    pub grpc_listen: Option<SocketAddr>,
+    /// How many `StreamingRecognize` calls may run at once
+    pub grpc_max_streams: usize,
     pub dump_dir: String,
+    pub dump_mode: DumpMode,
+    pub dump_retention: Retention,
     pub warmup_dir: String,
     pub warmup_cycles: i32,
+    /// Seconds to warm up for, instead of `warmup_cycles` when not 0
+    pub warmup_budget: u64,
+    /// Seconds of generated audio to warm up on without `warmup_dir`, 0 for none
+    pub warmup_synthetic_duration: u64,
     pub model: String,
     pub scorer: String,
+    pub version_file: Option<String>,
+    pub shutdown_grace_period: u64,
     pub verbosity_level: VerbosityLevel,
+    pub log_format: LogFormat,
+    pub access_log: Option<String>,
+    pub access_log_format: AccessLogFormat,
+    pub access_log_max_size: u64,
+    pub access_log_max_files: u32,
+    pub tls: Option<TlsOptions>,
+    pub auth_file: Option<String>,
+    pub max_body_size: u64,
+    pub max_audio_duration: u64,
+    pub rate_limit: f64,
+    pub rate_limit_burst: u32,
+    pub cors: Option<CorsConfig>,
+    pub subtitles: SubtitleOptions,
+    pub postprocess: bool,
+    pub replacements_file: Option<String>,
+    pub profanity_file: Option<String>,
+    pub redaction_patterns_file: Option<String>,
+    pub cache_size: usize,
+    pub cache_dir: Option<String>,
+    pub cache_dir_max_entries: usize,
+    pub check_config: bool,
+    pub command: Option<Command>,
 }
 
-pub struct ArgsParser;
+#[derive(Debug, Clone, PartialEq)]
+/// A tool to run instead of the server
+pub enum Command {
+    Replay(ReplayOptions),
+    Transcribe(OfflineOptions),
+    Evaluate(EvaluateOptions),
+    Bench(BenchOptions),
+}
 
-impl ArgsParser {
-    fn to_ip_addr(o: Option<&str>) -> IpAddr {
-        let default_ip = IpAddr::V6(Ipv6Addr::from_str("::0").unwrap());
-        match o {
-            Some(ip_str) => {
-                if Ipv6Addr::from_str(ip_str).is_ok() {
-                    IpAddr::V6(Ipv6Addr::from_str(ip_str).unwrap())
-                } else if Ipv4Addr::from_str(ip_str).is_ok() {
-                    IpAddr::V4(Ipv4Addr::from_str(ip_str).unwrap())
-                } else {
-                    default_ip
+impl Command {
+    fn needs_model(&self) -> bool {
+        match *self {
+            Command::Replay(ref options) => options.server.is_none(),
+            Command::Transcribe(_) | Command::Evaluate(_) => true,
+            Command::Bench(ref options) => options.server.is_none(),
+        }
+    }
+}
+
+impl RuntimeConfig {
+    /// Where to serve HTTP over TCP: `http_listen`, or `http_ip`:`http_port`
+    /// with every route.
+    pub fn listeners(&self) -> Vec<ListenAddr> {
+        if !self.http_tcp {
+            Vec::new()
+        } else if self.http_listen.is_empty() {
+            vec![ListenAddr {
+                addr: SocketAddr::new(self.http_ip, self.http_port),
+                routes: RouteSet::ALL,
+            }]
+        } else {
+            self.http_listen.clone()
+        }
+    }
+
+    /// Ensure every path we will need later is actually there, so that a
+    /// misconfigured deployment fails at startup rather than on first use.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.command.as_ref().map(Command::needs_model).unwrap_or(true) {
+            ArgsParser::ensure_file("model", &self.model)?;
+            ArgsParser::ensure_file("scorer", &self.scorer)?;
+        }
+        match self.command {
+            Some(Command::Replay(ref options)) => {
+                ArgsParser::ensure_dir("DUMP_DIR", &options.dump_dir)?;
+            }
+            Some(Command::Transcribe(OfflineOptions {
+                output_dir: Some(ref output_dir),
+                ..
+            })) => {
+                ArgsParser::ensure_dir("output_dir", output_dir)?;
+            }
+            Some(Command::Evaluate(ref options)) => {
+                for manifest in options.manifests.iter() {
+                    ArgsParser::ensure_file("MANIFEST", manifest)?;
                 }
             }
-            None => default_ip,
+            Some(Command::Bench(BenchOptions { server: None, .. })) if self.tls.is_some() => {
+                return Err(ConfigError::new(
+                    "tls_cert",
+                    "in-process benchmarks only speak plain HTTP".to_string(),
+                ));
+            }
+            Some(Command::Bench(BenchOptions { server: None, .. })) if !self.http_tcp => {
+                return Err(ConfigError::new(
+                    "http_tcp",
+                    "in-process benchmarks need HTTP over TCP".to_string(),
+                ));
+            }
+            Some(Command::Bench(BenchOptions { server: None, .. }))
+                if !self.http_listen.is_empty() =>
+            {
+                return Err(ConfigError::new(
+                    "http_listen",
+                    "in-process benchmarks listen on http_port".to_string(),
+                ));
+            }
+            _ => {}
+        }
+        if !self.http_tcp && self.unix_socket.is_none() {
+            return Err(ConfigError::new(
+                "http_tcp",
+                "HTTP must be served over TCP or on --http_socket".to_string(),
+            ));
+        }
+        if !self.http_tcp && !self.http_listen.is_empty() {
+            return Err(ConfigError::new(
+                "http_listen",
+                "HTTP over TCP is disabled by http_tcp".to_string(),
+            ));
         }
+        if let Some(addr) = self.grpc_listen {
+            if self.tls.is_some() {
+                return Err(ConfigError::new(
+                    "grpc_listen",
+                    "the gRPC API is only served in plain text, not with tls_cert".to_string(),
+                ));
+            }
+            if self.listeners().iter().any(|listener| listen::overlaps(listener.addr, addr)) {
+                return Err(ConfigError::new(
+                    "grpc_listen",
+                    "must differ from the HTTP addresses".to_string(),
+                ));
+            }
+        }
+        if self.dump_mode != DumpMode::Off {
+            ArgsParser::ensure_dir("dump_dir", &self.dump_dir)?;
+        }
+        if !self.warmup_dir.is_empty() {
+            ArgsParser::ensure_dir("warmup_dir", &self.warmup_dir)?;
+        }
+        if let Some(ref version_file) = self.version_file {
+            ArgsParser::ensure_file("version_file", version_file)?;
+        }
+        if let Some(ref auth_file) = self.auth_file {
+            ArgsParser::ensure_file("auth_file", auth_file)?;
+        }
+        if let Some(ref replacements_file) = self.replacements_file {
+            ArgsParser::ensure_file("replacements_file", replacements_file)?;
+        }
+        if let Some(ref profanity_file) = self.profanity_file {
+            ArgsParser::ensure_file("profanity_file", profanity_file)?;
+        }
+        if let Some(ref redaction_patterns_file) = self.redaction_patterns_file {
+            ArgsParser::ensure_file("redaction_patterns_file", redaction_patterns_file)?;
+        }
+        if let Some(ref cache_dir) = self.cache_dir {
+            ArgsParser::ensure_dir("cache_dir", cache_dir)?;
+        }
+        if let Some(ref tls) = self.tls {
+            ArgsParser::ensure_file("tls_cert", &tls.cert)?;
+            ArgsParser::ensure_file("tls_key", &tls.key)?;
+            if let Some(ref ca) = tls.client_ca {
+                ArgsParser::ensure_file("tls_client_ca", ca)?;
+            }
+        }
+
+        Ok(())
     }
+}
 
-    fn to_port(o: Option<&str>) -> TcpPort {
-        let default_port = 8080;
-        match o.unwrap_or(default_port.to_string().as_str())
-            .parse::<u16>()
-        {
-            Ok(rv) => rv,
-            Err(_) => default_port,
+pub struct ArgsParser;
+
+impl ArgsParser {
+    fn to_ip_addr(ip_str: &str) -> Result<IpAddr, String> {
+        if let Ok(ip) = Ipv6Addr::from_str(ip_str) {
+            Ok(IpAddr::V6(ip))
+        } else if let Ok(ip) = Ipv4Addr::from_str(ip_str) {
+            Ok(IpAddr::V4(ip))
+        } else {
+            Err("not an IPv4 or IPv6 address".to_string())
+        }
+    }
+
+    fn to_port(port_str: &str) -> Result<TcpPort, String> {
+        match port_str.parse::<TcpPort>() {
+            Ok(0) => Err("port must be between 1 and 65535".to_string()),
+            Ok(port) => Ok(port),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    fn to_cycles(cycles_str: &str) -> Result<i32, String> {
+        match cycles_str.parse::<i32>() {
+            Ok(cycles) if cycles < 1 => Err("must be at least 1".to_string()),
+            Ok(cycles) => Ok(cycles),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    fn ensure_file(key: &str, path: &str) -> Result<(), ConfigError> {
+        if Path::new(path).is_file() {
+            Ok(())
+        } else {
+            Err(ConfigError::new(key, format!("{:?} is not an existing file", path)))
+        }
+    }
+
+    fn ensure_dir(key: &str, path: &str) -> Result<(), ConfigError> {
+        if Path::new(path).is_dir() {
+            Ok(())
+        } else {
+            Err(ConfigError::new(key, format!("{:?} is not an existing directory", path)))
         }
     }
 
@@ -77,11 +347,144 @@ impl ArgsParser {
         }
     }
 
-    pub fn from_cli() -> RuntimeConfig {
-        let matches = clap::App::new("DeepSpeech Inference Server")
+    fn to_verbosity(level_str: &str) -> Result<VerbosityLevel, String> {
+        match level_str.to_lowercase().as_str() {
+            "error" => Ok(VerbosityLevel::ERROR),
+            "warn" => Ok(VerbosityLevel::WARN),
+            "info" => Ok(VerbosityLevel::INFO),
+            "debug" => Ok(VerbosityLevel::DEBUG),
+            other => other
+                .parse::<u64>()
+                .map(ArgsParser::to_verbosity_level)
+                .map_err(|_| "expected error, warn, info, debug or a number".to_string()),
+        }
+    }
+
+    fn to_log_format(format_str: &str) -> Result<LogFormat, String> {
+        match format_str.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected text or json".to_string()),
+        }
+    }
+
+    fn to_access_log_format(format_str: &str) -> Result<AccessLogFormat, String> {
+        match format_str.to_lowercase().as_str() {
+            "combined" => Ok(AccessLogFormat::Combined),
+            "json" => Ok(AccessLogFormat::Json),
+            _ => Err("expected combined or json".to_string()),
+        }
+    }
+
+    fn to_rate(rate_str: &str) -> Result<f64, String> {
+        match rate_str.parse::<f64>() {
+            Ok(rate) if rate.is_finite() && rate >= 0.0 => Ok(rate),
+            Ok(_) => Err("must be a positive number".to_string()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    fn to_line_length(length_str: &str) -> Result<usize, String> {
+        match length_str.parse::<usize>() {
+            Ok(0) => Err("must be at least 1".to_string()),
+            Ok(length) => Ok(length),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    fn to_seconds(seconds_str: &str) -> Result<f32, String> {
+        match seconds_str.parse::<f32>() {
+            Ok(seconds) if seconds.is_finite() && seconds > 0.0 => Ok(seconds),
+            Ok(_) => Err("must be a positive number of seconds".to_string()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    fn to_bool(bool_str: &str) -> Result<bool, String> {
+        match bool_str.to_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => Ok(true),
+            "false" | "no" | "off" | "0" => Ok(false),
+            _ => Err("expected true or false".to_string()),
+        }
+    }
+
+    fn to_string(value: &str) -> Result<String, String> {
+        Ok(value.to_string())
+    }
+
+    fn required(values: &ConfigValues, key: &str) -> Result<String, ConfigError> {
+        match values.get(key) {
+            Some(v) => Ok(v.value.clone()),
+            None => Err(ConfigError::new(
+                key,
+                format!(
+                    "missing, set --{}, {} or `{}` in the config file",
+                    key,
+                    env_name(key),
+                    key
+                ),
+            )),
+        }
+    }
+
+    fn tls_options(values: &ConfigValues) -> Result<Option<TlsOptions>, ConfigError> {
+        let get = |key| values.get(key).map(|v| v.value.clone());
+        match (get("tls_cert"), get("tls_key"), get("tls_client_ca")) {
+            (Some(cert), Some(key), client_ca) => Ok(Some(TlsOptions {
+                cert: cert,
+                key: key,
+                client_ca: client_ca,
+            })),
+            (None, None, None) => Ok(None),
+            (None, _, _) => Err(ConfigError::new(
+                "tls_cert",
+                "required when tls_key or tls_client_ca is set".to_string(),
+            )),
+            (Some(_), None, _) => Err(ConfigError::new(
+                "tls_key",
+                "required when tls_cert is set".to_string(),
+            )),
+        }
+    }
+
+    fn cors_config(values: &ConfigValues) -> Result<Option<CorsConfig>, ConfigError> {
+        let origins = match values.get("cors_allowed_origins") {
+            Some(v) => split_list(&v.value),
+            None => return Ok(None),
+        };
+
+        Ok(Some(CorsConfig {
+            allowed_origins: origins,
+            allowed_methods: values.parse(
+                "cors_allowed_methods",
+                split_list("GET, POST, OPTIONS"),
+                |v| Ok(split_list(v)),
+            )?,
+            allowed_headers: values.parse(
+                "cors_allowed_headers",
+                split_list("Content-Type, Accept, Authorization, X-Api-Key, X-Request-Id"),
+                |v| Ok(split_list(v)),
+            )?,
+            max_age: values.parse("cors_max_age", 600, |v| {
+                v.parse::<u64>().map_err(|e| e.to_string())
+            })?,
+        }))
+    }
+
+    fn app<'a, 'b>() -> clap::App<'a, 'b> {
+        clap::App::new("DeepSpeech Inference Server")
             .version("0.1")
             .author("<lissyx@lissyx.dyndns.org>")
             .about("Running inference from POST-ed RAW PCM.")
+            .arg(
+                clap::Arg::with_name("config")
+                    .long("config")
+                    .value_name("CONFIG")
+                    .help("TOML or YAML file to read settings from")
+                    .takes_value(true)
+                    .required(false)
+                    .global(true),
+            )
             .arg(
                 clap::Arg::with_name("http_ip")
                     .short("h")
@@ -100,6 +503,65 @@ impl ArgsParser {
                     .takes_value(true)
                     .required(false),
             )
+            .arg(
+                clap::Arg::with_name("http_listen")
+                    .long("http_listen")
+                    .value_name("ADDRESS:PORT[=ROUTES],...")
+                    .help(
+                        "Addresses to serve HTTP on instead, each with api, health, admin \
+                         or all routes, like 0.0.0.0:8080=api+health,127.0.0.1:9090=admin",
+                    )
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("http_ipv6_only")
+                    .long("http_ipv6_only")
+                    .value_name("BOOL")
+                    .help("Refuse IPv4 connections on IPv6 addresses such as :: [default: false]")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("http_tcp")
+                    .long("http_tcp")
+                    .value_name("BOOL")
+                    .help("Serve HTTP over TCP, set to false to only serve on --http_socket")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("http_socket")
+                    .long("http_socket")
+                    .value_name("PATH")
+                    .help("Unix domain socket to serve plain HTTP on, besides TCP")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("http_socket_mode")
+                    .long("http_socket_mode")
+                    .value_name("MODE")
+                    .help("Octal permissions of the --http_socket file [default: 660]")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("grpc_listen")
+                    .long("grpc_listen")
+                    .value_name("ADDRESS:PORT")
+                    .help("Address to serve the gRPC API on, like [::]:50051")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("grpc_max_streams")
+                    .long("grpc_max_streams")
+                    .value_name("GRPC_MAX_STREAMS")
+                    .help("Concurrent gRPC StreamingRecognize calls allowed [default: 16]")
+                    .takes_value(true)
+                    .required(false),
+            )
             .arg(
                 clap::Arg::with_name("dump_dir")
                     .short("d")
@@ -109,6 +571,38 @@ impl ArgsParser {
                     .takes_value(true)
                     .required(false),
             )
+            .arg(
+                clap::Arg::with_name("dump_mode")
+                    .long("dump_mode")
+                    .value_name("MODE")
+                    .help("Which requests to dump: off, all, failures or a percentage like 10%")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("dump_max_files")
+                    .long("dump_max_files")
+                    .value_name("DUMPS")
+                    .help("How many dumps to keep, 0 for no limit")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("dump_max_age")
+                    .long("dump_max_age")
+                    .value_name("SECONDS")
+                    .help("How long to keep dumps for, 0 for no limit")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("dump_max_size")
+                    .long("dump_max_size")
+                    .value_name("BYTES")
+                    .help("How much space dumps may use, 0 for no limit")
+                    .takes_value(true)
+                    .required(false),
+            )
             .arg(
                 clap::Arg::with_name("warmup_dir")
                     .short("w")
@@ -123,7 +617,23 @@ impl ArgsParser {
                     .short("c")
                     .long("warmup_cycles")
                     .value_name("WARMUP_CYCLES")
-                    .help("How many warmup cycles to perform for each WAVE in WARMUP_DIR")
+                    .help("How many times to transcribe each warmup file, 10 or 1 without WARMUP_DIR")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("warmup_budget")
+                    .long("warmup_budget")
+                    .value_name("SECONDS")
+                    .help("Warm up for that long instead of a number of cycles, 0 to count cycles")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("warmup_synthetic_duration")
+                    .long("warmup_synthetic_duration")
+                    .value_name("SECONDS")
+                    .help("Seconds of generated audio to warm up on without WARMUP_DIR, 0 for none")
                     .takes_value(true)
                     .required(false),
             )
@@ -134,7 +644,8 @@ impl ArgsParser {
                     .value_name("MODEL")
                     .help("TensorFlow model to use")
                     .takes_value(true)
-                    .required(true),
+                    .required(false)
+                    .global(true),
             )
             .arg(
                 clap::Arg::with_name("scorer")
@@ -143,66 +654,735 @@ impl ArgsParser {
                     .value_name("Scorer")
                     .help("External scorer to use")
                     .takes_value(true)
-                    .required(true),
+                    .required(false)
+                    .global(true),
+            )
+            .arg(
+                clap::Arg::with_name("version_file")
+                    .long("version_file")
+                    .value_name("VERSION_FILE")
+                    .help("JSON file served on /__version__ instead of the embedded build metadata")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("shutdown_grace_period")
+                    .short("g")
+                    .long("shutdown_grace_period")
+                    .value_name("SHUTDOWN_GRACE_PERIOD")
+                    .help("How long to wait for in-flight inferences on SIGTERM/SIGINT")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("log_format")
+                    .long("log_format")
+                    .value_name("LOG_FORMAT")
+                    .help("Log output format: text or json (one JSON object per line)")
+                    .takes_value(true)
+                    .required(false)
+                    .global(true),
+            )
+            .arg(
+                clap::Arg::with_name("access_log")
+                    .long("access_log")
+                    .value_name("ACCESS_LOG")
+                    .help("File to write one access log line per HTTP request to")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("access_log_format")
+                    .long("access_log_format")
+                    .value_name("ACCESS_LOG_FORMAT")
+                    .help("Access log format: combined or json")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("access_log_max_size")
+                    .long("access_log_max_size")
+                    .value_name("BYTES")
+                    .help("Rotate the access log once it grows past this size, 0 to never rotate")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("access_log_max_files")
+                    .long("access_log_max_files")
+                    .value_name("COUNT")
+                    .help("How many rotated access logs to keep")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("tls_cert")
+                    .long("tls-cert")
+                    .value_name("TLS_CERT")
+                    .help("PEM certificate chain to serve HTTPS with, reloaded when changed")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("tls_key")
+                    .long("tls-key")
+                    .value_name("TLS_KEY")
+                    .help("PEM private key matching TLS_CERT, reloaded when changed")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("tls_client_ca")
+                    .long("tls-client-ca")
+                    .value_name("TLS_CLIENT_CA")
+                    .help("PEM CA bundle to require and verify client certificates against")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("auth_file")
+                    .long("auth_file")
+                    .value_name("AUTH_FILE")
+                    .help("TOML file of API keys required to POST audio")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("max_body_size")
+                    .long("max_body_size")
+                    .value_name("BYTES")
+                    .help("Largest request body accepted, 0 for no limit")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("max_audio_duration")
+                    .long("max_audio_duration")
+                    .value_name("SECONDS")
+                    .help("Longest decoded audio accepted, 0 for no limit")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("rate_limit")
+                    .long("rate_limit")
+                    .value_name("REQUESTS_PER_SECOND")
+                    .help("Requests per second allowed from each client IP, 0 for no limit")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("rate_limit_burst")
+                    .long("rate_limit_burst")
+                    .value_name("REQUESTS")
+                    .help("Requests a client IP may send at once before being rate limited")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("cors_allowed_origins")
+                    .long("cors_allowed_origins")
+                    .value_name("ORIGINS")
+                    .help("Comma-separated origins allowed to call us from a browser, * for any")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("cors_allowed_methods")
+                    .long("cors_allowed_methods")
+                    .value_name("METHODS")
+                    .help("Comma-separated methods allowed in CORS preflight requests")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("cors_allowed_headers")
+                    .long("cors_allowed_headers")
+                    .value_name("HEADERS")
+                    .help("Comma-separated request headers allowed in CORS preflight requests")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("cors_max_age")
+                    .long("cors_max_age")
+                    .value_name("SECONDS")
+                    .help("How long browsers may cache CORS preflight responses")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("subtitle_max_line_length")
+                    .long("subtitle_max_line_length")
+                    .value_name("CHARACTERS")
+                    .help("Longest subtitle line produced for SRT and WebVTT output")
+                    .takes_value(true)
+                    .required(false)
+                    .global(true),
+            )
+            .arg(
+                clap::Arg::with_name("subtitle_max_cue_duration")
+                    .long("subtitle_max_cue_duration")
+                    .value_name("SECONDS")
+                    .help("Longest time a subtitle cue stays on screen")
+                    .takes_value(true)
+                    .required(false)
+                    .global(true),
+            )
+            .arg(
+                clap::Arg::with_name("postprocess")
+                    .long("postprocess")
+                    .value_name("BOOL")
+                    .help("Add formatted_text with numbers, dates, capitalization and replacements")
+                    .takes_value(true)
+                    .required(false)
+                    .global(true),
+            )
+            .arg(
+                clap::Arg::with_name("replacements_file")
+                    .long("replacements_file")
+                    .value_name("REPLACEMENTS_FILE")
+                    .help("TOML file of phrases to replace in formatted_text")
+                    .takes_value(true)
+                    .required(false)
+                    .global(true),
+            )
+            .arg(
+                clap::Arg::with_name("profanity_file")
+                    .long("profanity_file")
+                    .value_name("PROFANITY_FILE")
+                    .help("Words masked with mask_profanity=true, one per line, * ending stems")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("redaction_patterns_file")
+                    .long("redaction_patterns_file")
+                    .value_name("PATTERNS_FILE")
+                    .help("TOML table of named regular expressions redacted with redact_pii=true")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("cache_size")
+                    .long("cache_size")
+                    .value_name("ENTRIES")
+                    .help("How many results to keep in memory for resubmitted audio, 0 to disable")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("cache_dir")
+                    .long("cache_dir")
+                    .value_name("CACHE_DIR")
+                    .help("Directory to also keep cached results in, across restarts")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("cache_dir_max_entries")
+                    .long("cache_dir_max_entries")
+                    .value_name("ENTRIES")
+                    .help("How many results to keep in the cache directory, 0 for no limit")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                clap::Arg::with_name("check_config")
+                    .long("check-config")
+                    .help("Validate the configuration, load the model and exit")
+                    .takes_value(false)
+                    .required(false),
             )
             .arg(
                 clap::Arg::with_name("v")
                     .short("v")
                     .multiple(true)
+                    .global(true)
                     .help("Sets the level of verbosity"),
             )
-            .get_matches();
+            .subcommand(
+                clap::SubCommand::with_name("replay")
+                    .about("Transcribe dumped requests again and compare with what was recorded")
+                    .arg(
+                        clap::Arg::with_name("DUMP_DIR")
+                            .help("Directory holding the dumps, as written with --dump_mode")
+                            .required(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("server")
+                            .long("server")
+                            .value_name("URL")
+                            .help("Send audio to this server instead of loading --model")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("api_key")
+                            .long("api_key")
+                            .value_name("KEY")
+                            .help("API key to send to --server")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("report")
+                            .long("report")
+                            .value_name("REPORT_FILE")
+                            .help("Write a JSON report of every replayed dump there")
+                            .takes_value(true),
+                    ),
+            )
+            .subcommand(
+                clap::SubCommand::with_name("transcribe")
+                    .about("Transcribe audio files without starting the server")
+                    .arg(
+                        clap::Arg::with_name("FILES")
+                            .help("WAV or raw PCM files, glob patterns, or - for standard input")
+                            .multiple(true)
+                            .required(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("format")
+                            .long("format")
+                            .value_name("FORMAT")
+                            .help("Output format: json, text, srt or vtt")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("jobs")
+                            .short("j")
+                            .long("jobs")
+                            .value_name("JOBS")
+                            .help("How many models transcribe files in parallel")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("output_dir")
+                            .long("output_dir")
+                            .value_name("OUTPUT_DIR")
+                            .help("Write one output file per input there instead of printing")
+                            .takes_value(true),
+                    ),
+            )
+            .subcommand(
+                clap::SubCommand::with_name("evaluate")
+                    .about("Measure word and character error rates against reference transcripts")
+                    .arg(
+                        clap::Arg::with_name("MANIFEST")
+                            .help("DeepSpeech CSV files with wav_filename and transcript columns")
+                            .multiple(true)
+                            .required(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("alpha")
+                            .long("alpha")
+                            .value_name("VALUES")
+                            .help("Language model weights to try, as a,b,c or start:stop:step")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("beta")
+                            .long("beta")
+                            .value_name("VALUES")
+                            .help("Word insertion bonuses to try, as a,b,c or start:stop:step")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("beam_width")
+                            .long("beam_width")
+                            .value_name("VALUES")
+                            .help("Beam widths to try, as a,b,c or start:stop:step")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("report")
+                            .long("report")
+                            .value_name("REPORT_FILE")
+                            .help("Write a JSON report of every run and file there")
+                            .takes_value(true),
+                    ),
+            )
+            .subcommand(
+                clap::SubCommand::with_name("bench")
+                    .about("Measure throughput and latency by sending audio to a server")
+                    .arg(
+                        clap::Arg::with_name("CORPUS")
+                            .help("Audio files or glob patterns to send in turn")
+                            .multiple(true)
+                            .required(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("server")
+                            .long("server")
+                            .value_name("URL")
+                            .help("Server to drive, instead of starting one in-process")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("concurrency")
+                            .short("c")
+                            .long("concurrency")
+                            .value_name("REQUESTS")
+                            .help("Most requests waiting for an answer at once")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("rate")
+                            .long("rate")
+                            .value_name("REQUESTS_PER_SECOND")
+                            .help("Requests to start per second, as fast as possible otherwise")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("requests")
+                            .short("n")
+                            .long("requests")
+                            .value_name("REQUESTS")
+                            .help("How many requests to send, the corpus size by default")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("duration")
+                            .long("duration")
+                            .value_name("SECONDS")
+                            .help("Stop sending requests after this long")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("api_key")
+                            .long("api_key")
+                            .value_name("KEY")
+                            .help("API key to send with every request")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("report")
+                            .long("report")
+                            .value_name("REPORT_FILE")
+                            .help("Write the results as JSON there")
+                            .takes_value(true),
+                    ),
+            )
+    }
+
+    fn command(matches: &clap::ArgMatches) -> Result<Option<Command>, ConfigError> {
+        Ok(match matches.subcommand() {
+            ("replay", Some(replay)) => Some(Command::Replay(ReplayOptions {
+                dump_dir: replay.value_of("DUMP_DIR").unwrap().to_string(),
+                server: replay.value_of("server").map(String::from),
+                api_key: replay.value_of("api_key").map(String::from),
+                report: replay.value_of("report").map(String::from),
+            })),
+            ("transcribe", Some(transcribe)) => Some(Command::Transcribe(OfflineOptions {
+                inputs: transcribe
+                    .values_of("FILES")
+                    .unwrap()
+                    .map(String::from)
+                    .collect(),
+                format: match transcribe.value_of("format") {
+                    Some(name) => format_by_name(name).ok_or_else(|| {
+                        ConfigError::new("format", "expected json, text, srt or vtt".to_string())
+                    })?,
+                    None => ResponseFormat::Json,
+                },
+                jobs: match transcribe.value_of("jobs") {
+                    Some(jobs) => match jobs.parse::<usize>() {
+                        Ok(jobs) if jobs > 0 => jobs,
+                        _ => {
+                            return Err(ConfigError::new(
+                                "jobs",
+                                "expected a positive number".to_string(),
+                            ))
+                        }
+                    },
+                    None => 1,
+                },
+                output_dir: transcribe.value_of("output_dir").map(String::from),
+            })),
+            ("evaluate", Some(evaluate)) => {
+                let grid = |key: &str| match evaluate.value_of(key) {
+                    Some(values) => parse_grid(values).map_err(|e| ConfigError::new(key, e)),
+                    None => Ok(Vec::new()),
+                };
+                let (alphas, betas) = (grid("alpha")?, grid("beta")?);
+                if alphas.is_empty() != betas.is_empty() {
+                    return Err(ConfigError::new(
+                        "alpha",
+                        "alpha and beta must be given together".to_string(),
+                    ));
+                }
+                let beam_widths = grid("beam_width")?
+                    .into_iter()
+                    .map(|width| {
+                        let max = f64::from(u16::max_value());
+                        if width.fract() == 0.0 && width >= 1.0 && width <= max {
+                            Ok(width as u16)
+                        } else {
+                            Err(ConfigError::new(
+                                "beam_width",
+                                format!("{} is not a valid beam width", width),
+                            ))
+                        }
+                    })
+                    .collect::<Result<Vec<u16>, ConfigError>>()?;
+
+                Some(Command::Evaluate(EvaluateOptions {
+                    manifests: evaluate
+                        .values_of("MANIFEST")
+                        .unwrap()
+                        .map(String::from)
+                        .collect(),
+                    alphas: alphas.into_iter().map(|v| v as f32).collect(),
+                    betas: betas.into_iter().map(|v| v as f32).collect(),
+                    beam_widths: beam_widths,
+                    report: evaluate.value_of("report").map(String::from),
+                }))
+            }
+            ("bench", Some(bench)) => {
+                let positive = |key: &str| -> Result<Option<usize>, ConfigError> {
+                    match bench.value_of(key) {
+                        Some(value) => match value.parse::<usize>() {
+                            Ok(value) if value > 0 => Ok(Some(value)),
+                            _ => Err(ConfigError::new(
+                                key,
+                                "expected a positive number".to_string(),
+                            )),
+                        },
+                        None => Ok(None),
+                    }
+                };
+                let positive_float = |key: &str| -> Result<Option<f64>, ConfigError> {
+                    match bench.value_of(key) {
+                        Some(value) => ArgsParser::to_rate(value)
+                            .and_then(|v| {
+                                if v > 0.0 {
+                                    Ok(v)
+                                } else {
+                                    Err("expected a positive number".to_string())
+                                }
+                            })
+                            .map(Some)
+                            .map_err(|e| ConfigError::new(key, e)),
+                        None => Ok(None),
+                    }
+                };
 
-        RuntimeConfig {
-            http_ip: ArgsParser::to_ip_addr(matches.value_of("http_ip")),
-            http_port: ArgsParser::to_port(matches.value_of("http_port")),
-            dump_dir: String::from(matches.value_of("dump_dir").unwrap_or("/tmp")),
-            warmup_dir: String::from(matches.value_of("warmup_dir").unwrap_or("")),
-            warmup_cycles: matches
-                .value_of("warmup_cycles")
-                .unwrap_or("10")
-                .parse::<i32>()
-                .unwrap(),
-            model: String::from(matches.value_of("model").unwrap()),
-            scorer: String::from(matches.value_of("scorer").unwrap()),
-            verbosity_level: ArgsParser::to_verbosity_level(matches.occurrences_of("v")),
+                Some(Command::Bench(BenchOptions {
+                    server: bench.value_of("server").map(String::from),
+                    corpus: bench.values_of("CORPUS").unwrap().map(String::from).collect(),
+                    concurrency: positive("concurrency")?.unwrap_or(1),
+                    rate: positive_float("rate")?,
+                    requests: positive("requests")?,
+                    duration: positive_float("duration")?
+                        .map(|s| Duration::from_millis((s * 1000.0) as u64)),
+                    api_key: bench.value_of("api_key").map(String::from),
+                    report: bench.value_of("report").map(String::from),
+                }))
+            }
+            _ => None,
+        })
+    }
+
+    pub fn from_cli() -> Result<RuntimeConfig, ConfigError> {
+        ArgsParser::from_args(env::args_os(), env::vars())
+    }
+
+    /// Layer the config file, environment and command line into a
+    /// `RuntimeConfig`.
+    pub fn from_args<I, T, E>(args: I, vars: E) -> Result<RuntimeConfig, ConfigError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+        E: IntoIterator<Item = (String, String)>,
+    {
+        let matches = ArgsParser::app().get_matches_from(args);
+        let vars: Vec<(String, String)> = vars.into_iter().collect();
+        let mut values = ConfigValues::new();
+
+        let config_env = env_name("config");
+        let config_file = match matches.value_of("config") {
+            Some(path) => Some(path.to_string()),
+            None => vars
+                .iter()
+                .find(|&&(ref name, _)| *name == config_env)
+                .map(|&(_, ref path)| path.clone()),
+        };
+        if let Some(path) = config_file {
+            values.load_file(&path, CONFIG_KEYS)?;
         }
+
+        values.load_env(vars, CONFIG_KEYS);
+
+        for key in CONFIG_KEYS {
+            if let Some(value) = matches.value_of(key) {
+                values.set(key, value.to_string(), format!("command line flag --{}", key));
+            }
+        }
+        if matches.occurrences_of("v") > 0 {
+            values.set(
+                "verbosity",
+                matches.occurrences_of("v").to_string(),
+                "command line flag -v".to_string(),
+            );
+        }
+
+        let command = ArgsParser::command(&matches)?;
+        let needs_model = command.as_ref().map(Command::needs_model).unwrap_or(true);
+        let model_file = |key: &str| {
+            if needs_model {
+                ArgsParser::required(&values, key)
+            } else {
+                Ok(values.get(key).map(|v| v.value.clone()).unwrap_or_default())
+            }
+        };
+        let warmup_dir = values.parse("warmup_dir", String::from(""), ArgsParser::to_string)?;
+        // Generated audio takes seconds per pass, and one is enough for it
+        let default_cycles = if warmup_dir.is_empty() { 1 } else { 10 };
+
+        Ok(RuntimeConfig {
+            http_ip: values.parse(
+                "http_ip",
+                IpAddr::V6(Ipv6Addr::from_str("::0").unwrap()),
+                ArgsParser::to_ip_addr,
+            )?,
+            http_port: values.parse("http_port", 8080, ArgsParser::to_port)?,
+            http_listen: values.parse("http_listen", Vec::new(), listen::parse_listen)?,
+            http_ipv6_only: values.parse("http_ipv6_only", false, ArgsParser::to_bool)?,
+            http_tcp: values.parse("http_tcp", true, ArgsParser::to_bool)?,
+            unix_socket: match values.get("http_socket") {
+                Some(path) => Some(UnixSocketOptions {
+                    path: path.value.clone(),
+                    mode: values.parse("http_socket_mode", 0o660, unix_socket::parse_mode)?,
+                }),
+                None => None,
+            },
+            grpc_listen: values.parse("grpc_listen", None, |v| listen::parse_addr(v).map(Some))?,
+            grpc_max_streams: values.parse("grpc_max_streams", 16, |v| match v.parse::<usize>() {
+                Ok(0) => Err("must be at least 1".to_string()),
+                Ok(streams) => Ok(streams),
+                Err(err) => Err(err.to_string()),
+            })?,
+            dump_dir: values.parse("dump_dir", String::from("/tmp"), ArgsParser::to_string)?,
+            dump_mode: values.parse("dump_mode", DumpMode::Off, parse_mode)?,
+            dump_retention: Retention {
+                max_files: values.parse("dump_max_files", 1000, |v| {
+                    v.parse::<usize>().map_err(|e| e.to_string())
+                })?,
+                max_age: values.parse("dump_max_age", Duration::from_secs(7 * 24 * 3600), |v| {
+                    v.parse::<u64>()
+                        .map(Duration::from_secs)
+                        .map_err(|e| e.to_string())
+                })?,
+                max_size: values.parse("dump_max_size", 1024 * 1024 * 1024, |v| {
+                    v.parse::<u64>().map_err(|e| e.to_string())
+                })?,
+            },
+            warmup_dir: warmup_dir,
+            warmup_cycles: values.parse("warmup_cycles", default_cycles, ArgsParser::to_cycles)?,
+            warmup_budget: values.parse("warmup_budget", 0, |v| {
+                v.parse::<u64>().map_err(|e| e.to_string())
+            })?,
+            warmup_synthetic_duration: values.parse("warmup_synthetic_duration", 5, |v| {
+                v.parse::<u64>().map_err(|e| e.to_string())
+            })?,
+            model: model_file("model")?,
+            scorer: model_file("scorer")?,
+            version_file: values
+                .get("version_file")
+                .map(|v| v.value.clone()),
+            shutdown_grace_period: values.parse("shutdown_grace_period", 30, |v| {
+                v.parse::<u64>().map_err(|e| e.to_string())
+            })?,
+            verbosity_level: values.parse(
+                "verbosity",
+                VerbosityLevel::ERROR,
+                ArgsParser::to_verbosity,
+            )?,
+            log_format: values.parse("log_format", LogFormat::Text, ArgsParser::to_log_format)?,
+            access_log: values.get("access_log").map(|v| v.value.clone()),
+            access_log_format: values.parse(
+                "access_log_format",
+                AccessLogFormat::Combined,
+                ArgsParser::to_access_log_format,
+            )?,
+            access_log_max_size: values.parse("access_log_max_size", 100 * 1024 * 1024, |v| {
+                v.parse::<u64>().map_err(|e| e.to_string())
+            })?,
+            access_log_max_files: values.parse("access_log_max_files", 5, |v| {
+                v.parse::<u32>().map_err(|e| e.to_string())
+            })?,
+            tls: ArgsParser::tls_options(&values)?,
+            auth_file: values.get("auth_file").map(|v| v.value.clone()),
+            max_body_size: values.parse("max_body_size", 64 * 1024 * 1024, |v| {
+                v.parse::<u64>().map_err(|e| e.to_string())
+            })?,
+            max_audio_duration: values.parse("max_audio_duration", 0, |v| {
+                v.parse::<u64>().map_err(|e| e.to_string())
+            })?,
+            rate_limit: values.parse("rate_limit", 0.0, ArgsParser::to_rate)?,
+            rate_limit_burst: values.parse("rate_limit_burst", 10, |v| {
+                v.parse::<u32>().map_err(|e| e.to_string())
+            })?,
+            cors: ArgsParser::cors_config(&values)?,
+            subtitles: SubtitleOptions {
+                max_line_length: values.parse(
+                    "subtitle_max_line_length",
+                    42,
+                    ArgsParser::to_line_length,
+                )?,
+                max_cue_duration: values.parse(
+                    "subtitle_max_cue_duration",
+                    7.0,
+                    ArgsParser::to_seconds,
+                )?,
+            },
+            postprocess: values.parse("postprocess", false, ArgsParser::to_bool)?,
+            replacements_file: values.get("replacements_file").map(|v| v.value.clone()),
+            profanity_file: values.get("profanity_file").map(|v| v.value.clone()),
+            redaction_patterns_file: values
+                .get("redaction_patterns_file")
+                .map(|v| v.value.clone()),
+            cache_size: values.parse("cache_size", 0, |v| {
+                v.parse::<usize>().map_err(|e| e.to_string())
+            })?,
+            cache_dir: values.get("cache_dir").map(|v| v.value.clone()),
+            cache_dir_max_entries: values.parse("cache_dir_max_entries", 10000, |v| {
+                v.parse::<usize>().map_err(|e| e.to_string())
+            })?,
+            check_config: matches.is_present("check_config"),
+            command: command,
+        })
     }
 }
 
 #[test]
 fn test_to_ip_addr() {
+    assert!(ArgsParser::to_ip_addr("").is_err());
     assert_eq!(
-        ArgsParser::to_ip_addr(Some("")),
-        IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))
-    );
-    assert_eq!(
-        ArgsParser::to_ip_addr(Some("239.255.0.1")),
-        IpAddr::V4(Ipv4Addr::new(239, 255, 0, 1))
-    );
-    assert_eq!(
-        ArgsParser::to_ip_addr(Some("1.2.3.4")),
-        IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))
+        ArgsParser::to_ip_addr("239.255.0.1"),
+        Ok(IpAddr::V4(Ipv4Addr::new(239, 255, 0, 1)))
     );
     assert_eq!(
-        ArgsParser::to_ip_addr(Some("::1")),
-        IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))
+        ArgsParser::to_ip_addr("1.2.3.4"),
+        Ok(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)))
     );
     assert_eq!(
-        ArgsParser::to_ip_addr(Some("ffx3::1")),
-        IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))
+        ArgsParser::to_ip_addr("::1"),
+        Ok(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)))
     );
+    assert!(ArgsParser::to_ip_addr("ffx3::1").is_err());
     assert_eq!(
-        ArgsParser::to_ip_addr(Some("ff03::1")),
-        IpAddr::V6(Ipv6Addr::new(0xff03, 0, 0, 0, 0, 0, 0, 1))
+        ArgsParser::to_ip_addr("ff03::1"),
+        Ok(IpAddr::V6(Ipv6Addr::new(0xff03, 0, 0, 0, 0, 0, 0, 1)))
     );
 }
 
 #[test]
 fn test_to_port() {
-    assert_eq!(ArgsParser::to_port(Some("xxx")), 8080);
-    assert_eq!(ArgsParser::to_port(Some("8080")), 8080);
-    assert_eq!(ArgsParser::to_port(Some("1234")), 1234);
+    assert!(ArgsParser::to_port("xxx").is_err());
+    assert!(ArgsParser::to_port("65536").is_err());
+    assert!(ArgsParser::to_port("0").is_err());
+    assert_eq!(ArgsParser::to_port("8080"), Ok(8080));
+    assert_eq!(ArgsParser::to_port("1234"), Ok(1234));
 }
 
 #[test]
@@ -215,11 +1395,154 @@ fn test_to_verbosity_level() {
     assert_eq!(ArgsParser::to_verbosity_level(42), VerbosityLevel::DEBUG);
 }
 
+#[test]
+fn test_to_cycles() {
+    assert!(ArgsParser::to_cycles("0").is_err());
+    assert!(ArgsParser::to_cycles("-3").is_err());
+    assert!(ArgsParser::to_cycles("ten").is_err());
+    assert_eq!(ArgsParser::to_cycles("10"), Ok(10));
+}
+
 #[test]
 fn test_args() {
-    let rc = ArgsParser::from_cli();
+    let rc = ArgsParser::from_args(
+        vec!["ds-srv", "--model", "output_graph.pbmm", "--scorer", "kenlm.scorer"],
+        Vec::new(),
+    ).unwrap();
 
-    assert_eq!(rc.http_ip.to_string(), "0.0.0.0");
+    assert_eq!(rc.http_ip.to_string(), "::");
     assert_eq!(rc.http_port.to_string(), "8080");
     assert_eq!(rc.verbosity_level, VerbosityLevel::ERROR);
+    // A single pass over synthetic audio, unless asked otherwise
+    assert_eq!(rc.warmup_cycles, 1);
+    assert_eq!(rc.grpc_max_streams, 16);
+}
+
+#[test]
+fn test_args_layering() {
+    let vars = vec![
+        ("DS_SRV_HTTP_PORT".to_string(), "1234".to_string()),
+        ("DS_SRV_MODEL".to_string(), "env.pbmm".to_string()),
+        ("DS_SRV_SCORER".to_string(), "env.scorer".to_string()),
+        ("DS_SRV_VERBOSITY".to_string(), "info".to_string()),
+    ];
+    let rc = ArgsParser::from_args(vec!["ds-srv", "--model", "cli.pbmm"], vars).unwrap();
+
+    assert_eq!(rc.http_port, 1234);
+    assert_eq!(rc.model, "cli.pbmm");
+    assert_eq!(rc.scorer, "env.scorer");
+    assert_eq!(rc.verbosity_level, VerbosityLevel::INFO);
+}
+
+#[test]
+fn test_args_invalid_key() {
+    let vars = vec![("DS_SRV_WARMUP_CYCLES".to_string(), "ten".to_string())];
+    let err = ArgsParser::from_args(
+        vec!["ds-srv", "--model", "m", "--scorer", "s", "--http_port", "80"],
+        vars,
+    ).unwrap_err();
+    assert_eq!(err.key, "warmup_cycles");
+
+    let err = ArgsParser::from_args(vec!["ds-srv", "--scorer", "s"], Vec::new()).unwrap_err();
+    assert_eq!(err.key, "model");
+}
+
+#[test]
+fn test_validate() {
+    let rc = ArgsParser::from_args(
+        vec!["ds-srv", "--model", "/nonexistent.pbmm", "--scorer", "/nonexistent.scorer"],
+        Vec::new(),
+    ).unwrap();
+    assert_eq!(rc.validate().unwrap_err().key, "model");
+
+    let rc = ArgsParser::from_args(
+        vec![
+            "ds-srv", "--model", "Cargo.toml", "--scorer", "Cargo.toml",
+            "--grpc_listen", "[::]:9090",
+            "--tls-cert", "cert.pem", "--tls-key", "key.pem",
+        ],
+        Vec::new(),
+    ).unwrap();
+    assert_eq!(rc.validate().unwrap_err().key, "grpc_listen");
+}
+
+#[test]
+fn test_args_replay() {
+    let rc = ArgsParser::from_args(
+        vec!["ds-srv", "replay", "/tmp", "--server", "http://localhost:8080/"],
+        Vec::new(),
+    ).unwrap();
+    assert_eq!(
+        rc.command,
+        Some(Command::Replay(ReplayOptions {
+            dump_dir: "/tmp".to_string(),
+            server: Some("http://localhost:8080/".to_string()),
+            api_key: None,
+            report: None,
+        }))
+    );
+    assert!(rc.validate().is_ok());
+
+    let err = ArgsParser::from_args(vec!["ds-srv", "replay", "/tmp"], Vec::new()).unwrap_err();
+    assert_eq!(err.key, "model");
+}
+
+#[test]
+fn test_args_transcribe() {
+    let rc = ArgsParser::from_args(
+        vec!["ds-srv", "transcribe", "a.wav", "b*.wav", "-j", "4", "--format", "srt"],
+        vec![
+            ("DS_SRV_MODEL".to_string(), "model.pbmm".to_string()),
+            ("DS_SRV_SCORER".to_string(), "kenlm.scorer".to_string()),
+        ],
+    ).unwrap();
+    match rc.command {
+        Some(Command::Transcribe(ref options)) => {
+            assert_eq!(options.inputs, vec!["a.wav", "b*.wav"]);
+            assert_eq!(options.jobs, 4);
+            assert_eq!(
+                options.format,
+                ResponseFormat::Subtitles(::subtitles::SubtitleFormat::Srt)
+            );
+        }
+        ref other => panic!("unexpected command {:?}", other),
+    }
+
+    let err = ArgsParser::from_args(
+        vec!["ds-srv", "--model", "m", "--scorer", "s", "transcribe", "a.wav", "--jobs", "0"],
+        Vec::new(),
+    ).unwrap_err();
+    assert_eq!(err.key, "jobs");
+}
+
+#[test]
+fn test_args_bench() {
+    let rc = ArgsParser::from_args(
+        vec![
+            "ds-srv", "bench", "a.wav", "--server", "http://localhost:8080/", "-c", "8", "--rate",
+            "2.5", "--duration", "30",
+        ],
+        Vec::new(),
+    ).unwrap();
+    assert_eq!(
+        rc.command,
+        Some(Command::Bench(BenchOptions {
+            server: Some("http://localhost:8080/".to_string()),
+            corpus: vec!["a.wav".to_string()],
+            concurrency: 8,
+            rate: Some(2.5),
+            requests: None,
+            duration: Some(Duration::from_secs(30)),
+            api_key: None,
+            report: None,
+        }))
+    );
+
+    let err = ArgsParser::from_args(
+        vec!["ds-srv", "bench", "a.wav", "--server", "http://localhost:8080/", "-c", "0"],
+        Vec::new(),
+    ).unwrap_err();
+    assert_eq!(err.key, "concurrency");
+    let err = ArgsParser::from_args(vec!["ds-srv", "bench", "a.wav"], Vec::new()).unwrap_err();
+    assert_eq!(err.key, "model");
 }
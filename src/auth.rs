@@ -0,0 +1,351 @@
+extern crate hmac;
+extern crate sha2;
+extern crate toml;
+
+use self::hmac::{Hmac, Mac};
+use self::sha2::Sha256;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Deserialize)]
+/// One entry of the keys file
+pub struct KeyConfig {
+    pub id: String,
+    /// Static key, sent as `X-Api-Key: <key>` or `Authorization: Bearer <key>`
+    pub key: Option<String>,
+    /// Secret used to sign `Authorization: Bearer <id>.<expiry>.<signature>` tokens
+    pub hmac_secret: Option<String>,
+    pub requests_per_minute: Option<u32>,
+    pub audio_seconds_per_day: Option<f64>,
+    #[serde(default)]
+    pub disabled: bool,
+    /// May read every key's usage from `/__usage__`
+    #[serde(default)]
+    pub admin: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeysFile {
+    keys: Vec<KeyConfig>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct KeyUsage {
+    pub requests: u64,
+    pub rejected: u64,
+    pub audio_seconds: f64,
+    pub requests_this_minute: u32,
+    pub audio_seconds_today: f64,
+    #[serde(skip)]
+    minute: u64,
+    #[serde(skip)]
+    day: u64,
+}
+
+pub struct ApiKey {
+    pub config: KeyConfig,
+    usage: Mutex<KeyUsage>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthError {
+    /// No credentials, or credentials we do not recognize: 401
+    Unauthorized,
+    /// Valid credentials for a key that may not be used: 403
+    Forbidden,
+    /// The key is over one of its quotas: 429
+    QuotaExceeded,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Compare secrets without leaking how much of them matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+/// Sign `<id>.<expiry>` with `secret`, as clients are expected to.
+fn sign_token(id: &str, expiry: u64, secret: &str) -> String {
+    let payload = format!("{}.{}", id, expiry);
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+    mac.input(payload.as_bytes());
+    let signature: String = mac
+        .result()
+        .code()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("{}.{}", payload, signature)
+}
+
+impl ApiKey {
+    fn new(config: KeyConfig) -> ApiKey {
+        ApiKey {
+            config: config,
+            usage: Mutex::new(KeyUsage::default()),
+        }
+    }
+
+    pub fn usage(&self) -> KeyUsage {
+        let mut usage = self.usage.lock().unwrap();
+        usage.roll(now());
+        usage.clone()
+    }
+
+    /// Count one request against the quotas, refusing it if over any of them.
+    pub fn admit(&self) -> Result<(), AuthError> {
+        let mut usage = self.usage.lock().unwrap();
+        usage.roll(now());
+
+        let over_rate = match self.config.requests_per_minute {
+            Some(limit) => usage.requests_this_minute >= limit,
+            None => false,
+        };
+        let over_audio = match self.config.audio_seconds_per_day {
+            Some(limit) => usage.audio_seconds_today >= limit,
+            None => false,
+        };
+        if over_rate || over_audio {
+            usage.rejected += 1;
+            return Err(AuthError::QuotaExceeded);
+        }
+
+        usage.requests += 1;
+        usage.requests_this_minute += 1;
+        Ok(())
+    }
+
+    /// Charge audio about to be transcribed to this key, refusing it, and
+    /// charging nothing, if it would take the key over its daily audio quota.
+    pub fn charge_audio(&self, duration: Duration) -> Result<(), AuthError> {
+        let seconds = duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9;
+        let mut usage = self.usage.lock().unwrap();
+        usage.roll(now());
+        if let Some(limit) = self.config.audio_seconds_per_day {
+            if usage.audio_seconds_today + seconds > limit {
+                usage.rejected += 1;
+                return Err(AuthError::QuotaExceeded);
+            }
+        }
+        usage.audio_seconds += seconds;
+        usage.audio_seconds_today += seconds;
+        Ok(())
+    }
+}
+
+impl KeyUsage {
+    fn roll(&mut self, now: u64) {
+        if self.minute != now / 60 {
+            self.minute = now / 60;
+            self.requests_this_minute = 0;
+        }
+        if self.day != now / 86400 {
+            self.day = now / 86400;
+            self.audio_seconds_today = 0.0;
+        }
+    }
+}
+
+pub struct Authenticator {
+    keys: Vec<Arc<ApiKey>>,
+}
+
+impl Authenticator {
+    pub fn load(path: &str) -> Result<Authenticator, String> {
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut content))
+            .map_err(|e| format!("unable to read {:?}: {}", path, e))?;
+        let parsed: KeysFile =
+            toml::from_str(&content).map_err(|e| format!("unable to parse {:?}: {}", path, e))?;
+        Authenticator::from_keys(parsed.keys)
+    }
+
+    pub fn from_keys(keys: Vec<KeyConfig>) -> Result<Authenticator, String> {
+        for key in keys.iter() {
+            if key.key.is_none() && key.hmac_secret.is_none() {
+                return Err(format!("key {:?} needs either `key` or `hmac_secret`", key.id));
+            }
+        }
+
+        Ok(Authenticator {
+            keys: keys.into_iter().map(|k| Arc::new(ApiKey::new(k))).collect(),
+        })
+    }
+
+    fn find_static(&self, secret: &str) -> Option<Arc<ApiKey>> {
+        self.keys
+            .iter()
+            .find(|k| match k.config.key {
+                Some(ref key) => constant_time_eq(key.as_bytes(), secret.as_bytes()),
+                None => false,
+            })
+            .cloned()
+    }
+
+    fn find_token(&self, token: &str) -> Result<Arc<ApiKey>, AuthError> {
+        let parts: Vec<&str> = token.rsplitn(3, '.').collect();
+        if parts.len() != 3 {
+            return Err(AuthError::Unauthorized);
+        }
+        let (signature, expiry, id) = (parts[0], parts[1], parts[2]);
+
+        let key = self
+            .keys
+            .iter()
+            .find(|k| k.config.id == id && k.config.hmac_secret.is_some())
+            .ok_or(AuthError::Unauthorized)?;
+        let signature = from_hex(signature).ok_or(AuthError::Unauthorized)?;
+
+        let secret = key.config.hmac_secret.as_ref().unwrap();
+        let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+        mac.input(format!("{}.{}", id, expiry).as_bytes());
+        mac.verify(&signature).map_err(|_| AuthError::Unauthorized)?;
+
+        match expiry.parse::<u64>() {
+            Ok(expiry) if expiry > now() => Ok(key.clone()),
+            _ => Err(AuthError::Unauthorized),
+        }
+    }
+
+    /// Resolve the key for a request from its `X-Api-Key` or `Authorization`
+    /// header, and count the request against that key's quotas.
+    pub fn authenticate(
+        &self,
+        api_key: Option<&str>,
+        authorization: Option<&str>,
+    ) -> Result<Arc<ApiKey>, AuthError> {
+        let key = self.resolve(api_key, authorization)?;
+        key.admit()?;
+        Ok(key)
+    }
+
+    /// Resolve the key for a request without counting it against any quota.
+    pub fn resolve(
+        &self,
+        api_key: Option<&str>,
+        authorization: Option<&str>,
+    ) -> Result<Arc<ApiKey>, AuthError> {
+        let key = match (api_key, authorization) {
+            (Some(secret), _) => self.find_static(secret).ok_or(AuthError::Unauthorized)?,
+            (None, Some(auth)) if auth.starts_with("Bearer ") => {
+                let credentials = auth["Bearer ".len()..].trim();
+                match self.find_static(credentials) {
+                    Some(key) => key,
+                    None => self.find_token(credentials)?,
+                }
+            }
+            _ => return Err(AuthError::Unauthorized),
+        };
+
+        if key.config.disabled {
+            return Err(AuthError::Forbidden);
+        }
+        Ok(key)
+    }
+
+    pub fn usage(&self) -> BTreeMap<String, KeyUsage> {
+        self.keys
+            .iter()
+            .map(|k| (k.config.id.clone(), k.usage()))
+            .collect()
+    }
+}
+
+#[test]
+fn test_authenticate() {
+    let auth = Authenticator::from_keys(vec![
+        KeyConfig {
+            id: "static".to_string(),
+            key: Some("s3cr3t".to_string()),
+            hmac_secret: None,
+            requests_per_minute: Some(2),
+            audio_seconds_per_day: None,
+            disabled: false,
+            admin: false,
+        },
+        KeyConfig {
+            id: "signed".to_string(),
+            key: None,
+            hmac_secret: Some("signing".to_string()),
+            requests_per_minute: None,
+            audio_seconds_per_day: Some(1.0),
+            disabled: false,
+            admin: false,
+        },
+        KeyConfig {
+            id: "disabled".to_string(),
+            key: Some("off".to_string()),
+            hmac_secret: None,
+            requests_per_minute: None,
+            audio_seconds_per_day: None,
+            disabled: true,
+            admin: true,
+        },
+        KeyConfig {
+            id: "admin".to_string(),
+            key: Some("root".to_string()),
+            hmac_secret: None,
+            requests_per_minute: Some(1),
+            audio_seconds_per_day: None,
+            disabled: false,
+            admin: true,
+        },
+    ]).unwrap();
+
+    assert_eq!(auth.authenticate(None, None).err(), Some(AuthError::Unauthorized));
+    assert_eq!(auth.authenticate(Some("nope"), None).err(), Some(AuthError::Unauthorized));
+    assert_eq!(auth.authenticate(Some("off"), None).err(), Some(AuthError::Forbidden));
+
+    assert!(auth.authenticate(Some("s3cr3t"), None).is_ok());
+    assert!(auth.authenticate(None, Some("Bearer s3cr3t")).is_ok());
+    assert_eq!(auth.authenticate(Some("s3cr3t"), None).err(), Some(AuthError::QuotaExceeded));
+
+    let token = sign_token("signed", now() + 60, "signing");
+    let bearer = format!("Bearer {}", token);
+    let key = auth.authenticate(None, Some(&bearer)).unwrap();
+    // Refused as a whole, even with some quota left
+    assert_eq!(key.charge_audio(Duration::from_secs(2)).err(), Some(AuthError::QuotaExceeded));
+    assert!(key.charge_audio(Duration::from_millis(600)).is_ok());
+    assert!(key.charge_audio(Duration::from_millis(400)).is_ok());
+    assert_eq!(auth.authenticate(None, Some(&bearer)).err(), Some(AuthError::QuotaExceeded));
+
+    let expired = format!("Bearer {}", sign_token("signed", now() - 1, "signing"));
+    assert_eq!(auth.authenticate(None, Some(&expired)).err(), Some(AuthError::Unauthorized));
+    let forged = format!("Bearer {}", sign_token("signed", now() + 60, "guess"));
+    assert_eq!(auth.authenticate(None, Some(&forged)).err(), Some(AuthError::Unauthorized));
+
+    assert_eq!(auth.resolve(Some("off"), None).err(), Some(AuthError::Forbidden));
+    assert!(auth.resolve(Some("root"), None).unwrap().config.admin);
+    assert!(auth.resolve(Some("root"), None).is_ok());
+
+    let usage = auth.usage();
+    assert_eq!(usage["static"].requests, 2);
+    assert_eq!(usage["static"].rejected, 1);
+    assert_eq!(usage["signed"].audio_seconds_today, 1.0);
+    assert_eq!(usage["signed"].rejected, 2);
+    assert_eq!(usage["admin"].requests, 0);
+}
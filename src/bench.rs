@@ -0,0 +1,310 @@
+extern crate bytes;
+extern crate futures;
+extern crate hyper;
+extern crate serde_json;
+extern crate tokio;
+
+use self::bytes::Bytes;
+use self::futures::{future, stream, Future, Stream};
+use self::hyper::client::HttpConnector;
+use self::hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use self::hyper::{Body, Client, Method, Request, Uri};
+use self::tokio::runtime::Runtime;
+use self::tokio::timer::Interval;
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use inference::InferenceResult;
+use offline::{expand, Input};
+use stats::LatencySummary;
+
+#[derive(Debug, Clone, PartialEq)]
+/// Settings of `ds-srv bench`
+pub struct BenchOptions {
+    /// Base URL of the server to drive, instead of starting one in-process
+    pub server: Option<String>,
+    /// Audio files or glob patterns, sent in turn
+    pub corpus: Vec<String>,
+    /// Most requests waiting for an answer at once
+    pub concurrency: usize,
+    /// Requests started per second, as fast as answers allow otherwise
+    pub rate: Option<f64>,
+    /// How many requests to send, the corpus size by default
+    pub requests: Option<usize>,
+    /// Stop sending requests after this long
+    pub duration: Option<Duration>,
+    pub api_key: Option<String>,
+    pub report: Option<String>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+/// Durations from a `Server-Timing` header
+struct ServerTiming {
+    queue: Option<Duration>,
+    inference: Option<Duration>,
+    audio: Option<Duration>,
+}
+
+fn parse_server_timing(header: &str) -> ServerTiming {
+    let mut timing = ServerTiming::default();
+    for metric in header.split(',') {
+        let mut parts = metric.split(';').map(|p| p.trim());
+        let name = parts.next().unwrap_or("");
+        let duration = parts
+            .filter(|p| p.starts_with("dur="))
+            .filter_map(|p| p["dur=".len()..].parse::<f64>().ok())
+            .map(|ms| Duration::from_micros((ms * 1000.0) as u64))
+            .next();
+        match name {
+            "queue" => timing.queue = duration,
+            "inference" => timing.inference = duration,
+            "audio" => timing.audio = duration,
+            _ => {}
+        }
+    }
+    timing
+}
+
+/// What became of one request
+struct Outcome {
+    latency: Duration,
+    /// Why it failed, as a short label to count by
+    error: Option<String>,
+    timing: ServerTiming,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    pub target: String,
+    pub concurrency: usize,
+    pub rate: Option<f64>,
+    pub requests: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    /// Failures by kind: HTTP status, `ko` results or connection errors
+    pub errors: BTreeMap<String, usize>,
+    pub elapsed_s: f64,
+    pub throughput_rps: f64,
+    /// Seconds of audio transcribed per second
+    pub audio_throughput: f64,
+    pub latency_ms: LatencySummary,
+    pub queue_wait_ms: LatencySummary,
+    pub inference_ms: LatencySummary,
+    /// Time spent neither queued nor in inference: network, decoding, ...
+    pub overhead_ms: LatencySummary,
+}
+
+fn send(
+    client: &Client<HttpConnector>,
+    uri: &Uri,
+    api_key: &Option<String>,
+    audio: Bytes,
+) -> Box<dyn Future<Item = Outcome, Error = String> + Send> {
+    let mut request = Request::builder();
+    request
+        .method(Method::POST)
+        .uri(uri.clone())
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .header(CACHE_CONTROL, "no-cache");
+    if let Some(ref key) = *api_key {
+        request.header("X-Api-Key", key.as_str());
+    }
+    let request = match request.body(Body::from(audio)) {
+        Ok(request) => request,
+        Err(err) => return Box::new(future::err(err.to_string())),
+    };
+
+    let started = Instant::now();
+    Box::new(
+        client
+            .request(request)
+            .and_then(|response| {
+                let status = response.status();
+                let timing = response
+                    .headers()
+                    .get("server-timing")
+                    .and_then(|h| h.to_str().ok())
+                    .map(parse_server_timing)
+                    .unwrap_or_default();
+                response
+                    .into_body()
+                    .concat2()
+                    .map(move |body| (status, timing, body))
+            })
+            .then(move |rv| {
+                let latency = started.elapsed();
+                Ok(match rv {
+                    Ok((status, timing, body)) => {
+                        let error = if !status.is_success() {
+                            Some(format!("HTTP {}", status.as_u16()))
+                        } else {
+                            match serde_json::from_slice::<InferenceResult>(&body) {
+                                Ok(ref result) if result.is_ok() => None,
+                                Ok(_) => Some("ko".to_string()),
+                                Err(_) => Some("invalid response".to_string()),
+                            }
+                        };
+                        Outcome {
+                            latency: latency,
+                            error: error,
+                            timing: timing,
+                        }
+                    }
+                    Err(err) => {
+                        debug!("Request failed: {}", err);
+                        Outcome {
+                            latency: latency,
+                            error: Some("connection".to_string()),
+                            timing: ServerTiming::default(),
+                        }
+                    }
+                })
+            }),
+    )
+}
+
+/// Drive the server at `target` with the corpus, as configured, and measure
+/// how it copes.
+pub fn run(target: &str, options: &BenchOptions) -> Result<BenchReport, String> {
+    let uri = target
+        .parse::<Uri>()
+        .map_err(|e| format!("invalid server URL {:?}: {}", target, e))?;
+    if uri.scheme_str() != Some("http") {
+        return Err(format!("server URL {:?} must start with http://", target));
+    }
+
+    let mut corpus = Vec::new();
+    for input in expand(&options.corpus)? {
+        if input == Input::Stdin {
+            return Err("the corpus cannot be read from standard input".to_string());
+        }
+        let audio = input
+            .read()
+            .map_err(|e| format!("unable to read {}: {}", input.name(), e))?;
+        corpus.push(Bytes::from(audio));
+    }
+
+    let mut runtime = Runtime::new().map_err(|e| format!("unable to start runtime: {}", e))?;
+    let client = Client::new();
+
+    // Not measured: waits for the model to load, and checks the server is
+    // there at all
+    let warmup = runtime.block_on(send(&client, &uri, &options.api_key, corpus[0].clone()))?;
+    if let Some(ref error) = warmup.error {
+        if error == "connection" {
+            return Err(format!("unable to reach {}", target));
+        }
+    }
+
+    let total = match (options.requests, options.duration) {
+        (Some(requests), _) => requests,
+        (None, Some(_)) => usize::max_value(),
+        (None, None) => corpus.len(),
+    };
+    let ticks: Box<dyn Stream<Item = (), Error = String> + Send> = match options.rate {
+        Some(rate) => Box::new(
+            Interval::new_interval(Duration::from_nanos((1e9 / rate) as u64))
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        ),
+        None => Box::new(stream::repeat(())),
+    };
+
+    let started = Instant::now();
+    let deadline = options.duration.map(|duration| started + duration);
+    let (send_client, send_uri, api_key) = (client.clone(), uri.clone(), options.api_key.clone());
+    let requests = stream::iter_ok::<_, String>(0..total)
+        .zip(ticks)
+        .take_while(move |_| Ok(deadline.map(|d| Instant::now() < d).unwrap_or(true)))
+        .map(move |(i, _)| {
+            send(
+                &send_client,
+                &send_uri,
+                &api_key,
+                corpus[i % corpus.len()].clone(),
+            )
+        })
+        .buffer_unordered(options.concurrency.max(1))
+        .collect();
+    let outcomes = runtime.block_on(requests)?;
+    let elapsed = started.elapsed().as_secs_f64();
+
+    let mut errors = BTreeMap::new();
+    for error in outcomes.iter().filter_map(|o| o.error.as_ref()) {
+        *errors.entry(error.clone()).or_insert(0) += 1;
+    }
+    let succeeded: Vec<&Outcome> = outcomes.iter().filter(|o| o.error.is_none()).collect();
+    let durations = |f: &dyn Fn(&Outcome) -> Option<Duration>| {
+        succeeded.iter().filter_map(|o| f(o)).collect::<Vec<_>>()
+    };
+    let audio: f64 = durations(&|o| o.timing.audio).iter().map(|d| d.as_secs_f64()).sum();
+
+    Ok(BenchReport {
+        target: target.to_string(),
+        concurrency: options.concurrency,
+        rate: options.rate,
+        requests: outcomes.len(),
+        succeeded: succeeded.len(),
+        failed: outcomes.len() - succeeded.len(),
+        errors: errors,
+        elapsed_s: elapsed,
+        throughput_rps: if elapsed > 0.0 { succeeded.len() as f64 / elapsed } else { 0.0 },
+        audio_throughput: if elapsed > 0.0 { audio / elapsed } else { 0.0 },
+        latency_ms: LatencySummary::new(&durations(&|o| Some(o.latency))),
+        queue_wait_ms: LatencySummary::new(&durations(&|o| o.timing.queue)),
+        inference_ms: LatencySummary::new(&durations(&|o| o.timing.inference)),
+        overhead_ms: LatencySummary::new(&durations(&|o| {
+            let timing = &o.timing;
+            let server = timing.queue.unwrap_or_default() + timing.inference.unwrap_or_default();
+            o.latency.checked_sub(server)
+        })),
+    })
+}
+
+impl BenchReport {
+    /// Throughput and latencies, for reading in a terminal.
+    pub fn summary(&self) -> String {
+        let line = |name: &str, s: &LatencySummary| {
+            format!(
+                "  {:<10} mean {:>8.1}ms  p50 {:>8.1}ms  p90 {:>8.1}ms  \
+                 p99 {:>8.1}ms  max {:>8.1}ms\n",
+                name, s.mean, s.p50, s.p90, s.p99, s.max
+            )
+        };
+        let mut out = format!(
+            "{} requests to {} in {:.1}s ({} at once{}): {:.2} req/s, {:.2}s of audio per second\n",
+            self.requests,
+            self.target,
+            self.elapsed_s,
+            self.concurrency,
+            self.rate
+                .map(|rate| format!(", {} req/s", rate))
+                .unwrap_or_default(),
+            self.throughput_rps,
+            self.audio_throughput
+        );
+        out.push_str(&line("latency", &self.latency_ms));
+        out.push_str(&line("queue", &self.queue_wait_ms));
+        out.push_str(&line("inference", &self.inference_ms));
+        out.push_str(&line("overhead", &self.overhead_ms));
+        out.push_str(&format!("{} succeeded, {} failed", self.succeeded, self.failed));
+        for (kind, count) in self.errors.iter() {
+            out.push_str(&format!(", {} {}", count, kind));
+        }
+        out.push('\n');
+        out
+    }
+}
+
+#[test]
+fn test_parse_server_timing() {
+    assert_eq!(
+        parse_server_timing("queue;dur=12.5, inference;desc=\"model\";dur=300, cache;dur=1"),
+        ServerTiming {
+            queue: Some(Duration::from_micros(12_500)),
+            inference: Some(Duration::from_millis(300)),
+            audio: None,
+        }
+    );
+}
@@ -0,0 +1,300 @@
+extern crate byte_slice_cast;
+extern crate serde_json;
+extern crate sha2;
+
+use self::byte_slice_cast::*;
+use self::sha2::{Digest, Sha256};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use inference::InferenceResult;
+use version::{ModelIdentity, VersionInfo};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub disk: bool,
+    pub disk_entries: usize,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+/// Values by key, forgetting the least recently used past `capacity`.
+struct Lru<V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<String, (V, u64)>,
+    by_use: BTreeMap<u64, String>,
+}
+
+impl<V: Clone> Lru<V> {
+    fn new(capacity: usize) -> Lru<V> {
+        Lru {
+            capacity: capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<V> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(key)?;
+        self.by_use.remove(&entry.1);
+        self.by_use.insert(tick, key.to_string());
+        entry.1 = tick;
+        Some(entry.0.clone())
+    }
+
+    /// Insert `value`, returning the keys forgotten to make room for it.
+    fn put(&mut self, key: &str, value: V) -> Vec<String> {
+        let mut evicted = Vec::new();
+        if self.capacity == 0 {
+            return evicted;
+        }
+
+        self.tick += 1;
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.by_use.remove(&used);
+        }
+        while self.entries.len() >= self.capacity {
+            let oldest = match self.by_use.keys().next() {
+                Some(&tick) => tick,
+                None => break,
+            };
+            if let Some(oldest) = self.by_use.remove(&oldest) {
+                self.entries.remove(&oldest);
+                evicted.push(oldest);
+            }
+        }
+        self.entries.insert(key.to_string(), (value, self.tick));
+        self.by_use.insert(self.tick, key.to_string());
+        evicted
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.by_use.remove(&used);
+        }
+    }
+}
+
+/// Successful transcriptions by audio, so that resubmitted audio does not
+/// go through the model again. Results are kept before redaction, which
+/// depends on each request, so the disk cache holds raw transcripts.
+pub struct ResultCache {
+    /// What, besides the audio, the results depend on
+    identity: String,
+    memory: Mutex<Lru<InferenceResult>>,
+    dir: Option<PathBuf>,
+    /// Entries in `dir`, whose files are deleted when forgotten
+    disk: Mutex<Lru<()>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+fn describe(identity: &Option<ModelIdentity>) -> String {
+    match *identity {
+        Some(ref identity) => identity.sha256.clone(),
+        None => "unknown".to_string(),
+    }
+}
+
+impl ResultCache {
+    /// `decoder_options` describes every setting that changes results for
+    /// the same audio, beyond the model and scorer themselves. At most
+    /// `disk_capacity` entries are kept in `dir`, 0 meaning no limit.
+    pub fn new(
+        capacity: usize,
+        dir: Option<PathBuf>,
+        disk_capacity: usize,
+        version: &VersionInfo,
+        decoder_options: &str,
+    ) -> ResultCache {
+        let disk_capacity = match disk_capacity {
+            0 => usize::max_value(),
+            capacity => capacity,
+        };
+        let cache = ResultCache {
+            identity: format!(
+                "model={} scorer={} deepspeech={} {}",
+                describe(&version.model),
+                describe(&version.scorer),
+                version.deepspeech.native_version,
+                decoder_options
+            ),
+            memory: Mutex::new(Lru::new(capacity)),
+            dir: dir,
+            disk: Mutex::new(Lru::new(disk_capacity)),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        };
+        cache.load_disk();
+        cache
+    }
+
+    /// Index the entries already in `dir`, least recently written first, and
+    /// delete those over the limit.
+    fn load_disk(&self) {
+        let dir = match self.dir {
+            Some(ref dir) => dir,
+            None => return,
+        };
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                error!("Unable to list cache entries in {:?}: {:?}", dir, err);
+                return;
+            }
+        };
+        let mut found: Vec<(SystemTime, String)> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let path = entry.path();
+                if path.extension().map(|ext| ext != "json").unwrap_or(true) {
+                    return None;
+                }
+                let key = path.file_stem()?.to_str()?.to_string();
+                let modified = entry.metadata().and_then(|m| m.modified()).ok()?;
+                Some((modified, key))
+            })
+            .collect();
+        found.sort();
+
+        let mut disk = self.disk.lock().unwrap();
+        for (_, key) in found {
+            for evicted in disk.put(&key, ()) {
+                self.remove_disk(&evicted);
+            }
+        }
+    }
+
+    pub fn key(&self, samples: &[i16]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.input(self.identity.as_bytes());
+        hasher.input([0]);
+        hasher.input(samples.as_byte_slice().expect("i16 slices are byte slices"));
+        format!("{:x}", hasher.result())
+    }
+
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(format!("{}.json", key)))
+    }
+
+    fn read_disk(&self, key: &str) -> Option<InferenceResult> {
+        let path = self.path(key)?;
+        self.disk.lock().unwrap().get(key)?;
+        let read = File::open(&path)
+            .map_err(|err| format!("{:?}", err))
+            .and_then(|file| serde_json::from_reader(file).map_err(|err| format!("{:?}", err)));
+        match read {
+            Ok(result) => Some(result),
+            Err(err) => {
+                error!("Ignoring unreadable cache entry {:?}: {}", path, err);
+                self.disk.lock().unwrap().remove(key);
+                None
+            }
+        }
+    }
+
+    fn remove_disk(&self, key: &str) {
+        if let Some(path) = self.path(key) {
+            if let Err(err) = fs::remove_file(&path) {
+                error!("Unable to remove cache entry {:?}: {:?}", path, err);
+            }
+        }
+    }
+
+    fn write_disk(&self, key: &str, result: &InferenceResult) {
+        let path = match self.path(key) {
+            Some(path) => path,
+            None => return,
+        };
+        let evicted = self.disk.lock().unwrap().put(key, ());
+        for evicted in evicted {
+            self.remove_disk(&evicted);
+        }
+        let partial = path.with_extension("json.tmp");
+        let written = File::create(&partial)
+            .and_then(|mut f| f.write_all(serde_json::to_string(result).unwrap().as_bytes()))
+            .and_then(|_| fs::rename(&partial, &path));
+        if let Err(err) = written {
+            error!("Unable to write cache entry {:?}: {:?}", path, err);
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<InferenceResult> {
+        let found = self.memory.lock().unwrap().get(key);
+        let found = found.or_else(|| {
+            let result = self.read_disk(key)?;
+            self.memory.lock().unwrap().put(key, result.clone());
+            Some(result)
+        });
+
+        if found.is_some() {
+            self.hits.fetch_add(1, Ordering::SeqCst);
+        } else {
+            self.misses.fetch_add(1, Ordering::SeqCst);
+        }
+        found
+    }
+
+    /// Remember `result` for `key`, unless inference failed.
+    pub fn put(&self, key: &str, result: &InferenceResult) {
+        if !result.is_ok() {
+            return;
+        }
+        self.memory.lock().unwrap().put(key, result.clone());
+        self.write_disk(key, result);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let memory = self.memory.lock().unwrap();
+        CacheStats {
+            entries: memory.entries.len(),
+            capacity: memory.capacity,
+            disk: self.dir.is_some(),
+            disk_entries: self.disk.lock().unwrap().entries.len(),
+            hits: self.hits.load(Ordering::SeqCst),
+            misses: self.misses.load(Ordering::SeqCst),
+        }
+    }
+}
+
+#[test]
+fn test_lru() {
+    let ok = |text: &str| -> InferenceResult {
+        serde_json::from_value(json!({
+            "status": "ok",
+            "data": [{"text": text, "confidence": 1.0}],
+        })).unwrap()
+    };
+
+    let mut lru = Lru::new(2);
+    lru.put("a", ok("a"));
+    lru.put("b", ok("b"));
+    assert!(lru.get("a").is_some());
+    lru.put("c", ok("c"));
+    assert!(lru.get("b").is_none());
+    assert_eq!(lru.get("a").unwrap().best().unwrap().text, "a");
+    assert_eq!(lru.get("c").unwrap().best().unwrap().text, "c");
+    assert_eq!(lru.entries.len(), 2);
+    assert_eq!(lru.by_use.len(), 2);
+
+    let mut index = Lru::new(2);
+    assert!(index.put("a", ()).is_empty());
+    assert!(index.put("b", ()).is_empty());
+    assert!(index.get("a").is_some());
+    assert_eq!(index.put("c", ()), vec!["b".to_string()]);
+    index.remove("a");
+    assert_eq!(index.entries.len(), 1);
+    assert_eq!(index.by_use.len(), 1);
+}
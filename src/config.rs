@@ -0,0 +1,155 @@
+extern crate serde_json;
+extern crate serde_yaml;
+extern crate toml;
+
+use self::serde_json::Value;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Prefix of environment variables overriding configuration keys
+pub const ENV_PREFIX: &str = "DS_SRV_";
+
+#[derive(Debug, Clone, PartialEq)]
+/// A configuration problem, naming the key that caused it
+pub struct ConfigError {
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(key: &str, message: String) -> ConfigError {
+        ConfigError {
+            key: key.to_string(),
+            message: message,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}`: {}", self.key, self.message)
+    }
+}
+
+#[derive(Debug, Clone)]
+/// One configuration value and where it came from
+pub struct ConfigValue {
+    pub value: String,
+    pub origin: String,
+}
+
+/// Configuration values layered from lowest to highest precedence
+#[derive(Debug, Default)]
+pub struct ConfigValues {
+    values: HashMap<String, ConfigValue>,
+}
+
+impl ConfigValues {
+    pub fn new() -> ConfigValues {
+        ConfigValues {
+            values: HashMap::new(),
+        }
+    }
+
+    /// Record `value` for `key`, overriding anything set by a previous layer.
+    pub fn set(&mut self, key: &str, value: String, origin: String) {
+        self.values.insert(
+            key.to_string(),
+            ConfigValue {
+                value: value,
+                origin: origin,
+            },
+        );
+    }
+
+    pub fn get(&self, key: &str) -> Option<&ConfigValue> {
+        self.values.get(key)
+    }
+
+    /// Parse the value of `key` with `parse`, reporting failures against `key`.
+    pub fn parse<T, F>(&self, key: &str, default: T, parse: F) -> Result<T, ConfigError>
+    where
+        F: Fn(&str) -> Result<T, String>,
+    {
+        match self.get(key) {
+            Some(v) => parse(&v.value).map_err(|err| {
+                ConfigError::new(key, format!("invalid value {:?} (from {}): {}", v.value, v.origin, err))
+            }),
+            None => Ok(default),
+        }
+    }
+
+    /// Merge a configuration file, rejecting keys not listed in `known_keys`.
+    pub fn load_file(&mut self, path: &str, known_keys: &[&str]) -> Result<(), ConfigError> {
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut content))
+            .map_err(|err| ConfigError::new("config", format!("unable to read {:?}: {}", path, err)))?;
+
+        let ext = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+        let parsed: Value = match ext.as_str() {
+            "toml" => toml::from_str(&content).map_err(|e| e.to_string()),
+            "yaml" | "yml" => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+            _ => Err("unsupported extension, expected .toml, .yaml or .yml".to_string()),
+        }.map_err(|err| ConfigError::new("config", format!("unable to parse {:?}: {}", path, err)))?;
+
+        let table = match parsed {
+            Value::Object(table) => table,
+            Value::Null => return Ok(()),
+            _ => {
+                return Err(ConfigError::new(
+                    "config",
+                    format!("{:?} must contain a table of settings", path),
+                ))
+            }
+        };
+
+        for (key, value) in table {
+            if !known_keys.contains(&key.as_str()) {
+                return Err(ConfigError::new(&key, format!("unknown setting in {:?}", path)));
+            }
+
+            let value = match value {
+                Value::String(s) => s,
+                Value::Number(n) => n.to_string(),
+                Value::Bool(b) => b.to_string(),
+                _ => {
+                    return Err(ConfigError::new(
+                        &key,
+                        format!("expected a string, number or boolean in {:?}", path),
+                    ))
+                }
+            };
+            self.set(&key, value, format!("config file {:?}", path));
+        }
+
+        Ok(())
+    }
+
+    /// Merge `DS_SRV_*` variables for every key in `known_keys`.
+    pub fn load_env<I>(&mut self, vars: I, known_keys: &[&str])
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let vars: HashMap<String, String> = vars.into_iter().collect();
+        for key in known_keys {
+            let name = env_name(key);
+            if let Some(value) = vars.get(&name) {
+                self.set(key, value.clone(), format!("environment variable {}", name));
+            }
+        }
+    }
+}
+
+/// Name of the environment variable overriding `key`.
+pub fn env_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.to_uppercase())
+}
@@ -0,0 +1,131 @@
+extern crate hyper;
+
+use self::hyper::header::{
+    HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+    ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use self::hyper::{Body, Response, StatusCode};
+
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    /// Origins allowed to call us, `*` allowing any
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub max_age: u64,
+}
+
+/// Response headers browsers may expose to scripts
+const EXPOSED_HEADERS: &str = "X-Request-Id, Retry-After, Server-Timing";
+
+/// Split a comma-separated setting into its trimmed, non-empty items.
+pub fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+impl CorsConfig {
+    fn allow_any(&self) -> bool {
+        self.allowed_origins.iter().any(|o| o == "*")
+    }
+
+    /// The `Access-Control-Allow-Origin` value for a request from `origin`,
+    /// if it is allowed at all.
+    fn allowed_origin(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        let origin = origin?;
+        if self.allow_any() {
+            return Some(HeaderValue::from_static("*"));
+        }
+
+        let origin_str = origin.to_str().ok()?;
+        if self
+            .allowed_origins
+            .iter()
+            .any(|o| o.eq_ignore_ascii_case(origin_str))
+        {
+            Some(origin.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Add CORS headers to any response sent to an allowed `origin`.
+    pub fn apply(&self, origin: Option<&HeaderValue>, response: &mut Response<Body>) {
+        let headers = response.headers_mut();
+        if !self.allow_any() {
+            headers.append(VARY, HeaderValue::from_static("Origin"));
+        }
+
+        if let Some(origin) = self.allowed_origin(origin) {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            headers.insert(
+                ACCESS_CONTROL_EXPOSE_HEADERS,
+                HeaderValue::from_static(EXPOSED_HEADERS),
+            );
+        }
+    }
+
+    /// Answer an `OPTIONS` preflight request.
+    pub fn preflight(&self, request_headers: &HeaderMap) -> Response<Body> {
+        let method_allowed = request_headers
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|m| m.to_str().ok())
+            .map(|m| self.allowed_methods.iter().any(|a| a.eq_ignore_ascii_case(m)))
+            .unwrap_or(false);
+
+        if self.allowed_origin(request_headers.get(ORIGIN)).is_none() || !method_allowed {
+            debug!("Refusing CORS preflight: {:?}", request_headers);
+            return Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::empty())
+                .unwrap();
+        }
+
+        let mut builder = Response::builder();
+        builder
+            .status(StatusCode::NO_CONTENT)
+            .header(ACCESS_CONTROL_ALLOW_METHODS, self.allowed_methods.join(", ").as_str())
+            .header(ACCESS_CONTROL_ALLOW_HEADERS, self.allowed_headers.join(", ").as_str())
+            .header(ACCESS_CONTROL_MAX_AGE, self.max_age);
+        builder.body(Body::empty()).unwrap()
+    }
+}
+
+#[test]
+fn test_cors() {
+    let cors = CorsConfig {
+        allowed_origins: split_list("https://app.example.com, https://other.example.com"),
+        allowed_methods: split_list("POST, GET"),
+        allowed_headers: split_list("Content-Type"),
+        max_age: 600,
+    };
+
+    let mut request = HeaderMap::new();
+    request.insert(ORIGIN, HeaderValue::from_static("https://app.example.com"));
+    request.insert(ACCESS_CONTROL_REQUEST_METHOD, HeaderValue::from_static("POST"));
+    let preflight = cors.preflight(&request);
+    assert_eq!(preflight.status(), StatusCode::NO_CONTENT);
+    assert_eq!(preflight.headers()[ACCESS_CONTROL_ALLOW_METHODS], "POST, GET");
+
+    let mut response = Response::new(Body::empty());
+    cors.apply(request.get(ORIGIN), &mut response);
+    assert_eq!(
+        response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+        "https://app.example.com"
+    );
+    assert_eq!(
+        response.headers()[ACCESS_CONTROL_EXPOSE_HEADERS],
+        "X-Request-Id, Retry-After, Server-Timing"
+    );
+
+    let mut evil = HeaderMap::new();
+    evil.insert(ORIGIN, HeaderValue::from_static("https://evil.example.com"));
+    evil.insert(ACCESS_CONTROL_REQUEST_METHOD, HeaderValue::from_static("POST"));
+    assert_eq!(cors.preflight(&evil).status(), StatusCode::FORBIDDEN);
+    let mut response = Response::new(Body::empty());
+    cors.apply(evil.get(ORIGIN), &mut response);
+    assert!(response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+}
@@ -0,0 +1,317 @@
+extern crate chrono;
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use inference::{decode_audio, InferenceResult, AUDIO_SAMPLE_RATE};
+
+/// Prefix of every file we write, and may later delete
+const DUMP_PREFIX: &str = "ds-debug-";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DumpMode {
+    Off,
+    All,
+    /// Roughly this percentage of requests
+    Sampled(f64),
+    /// Requests that did not produce a transcription
+    Failures,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Limits on what is kept in the dump directory, 0 meaning no limit
+pub struct Retention {
+    pub max_files: usize,
+    pub max_age: Duration,
+    pub max_size: u64,
+}
+
+pub fn parse_mode(mode: &str) -> Result<DumpMode, String> {
+    match mode.to_lowercase().as_str() {
+        "off" => Ok(DumpMode::Off),
+        "all" => Ok(DumpMode::All),
+        "failures" => Ok(DumpMode::Failures),
+        other if other.ends_with('%') => match other[..other.len() - 1].parse::<f64>() {
+            Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(DumpMode::Sampled(percent)),
+            _ => Err("percentage must be between 0% and 100%".to_string()),
+        },
+        _ => Err("expected off, all, failures or a percentage like 10%".to_string()),
+    }
+}
+
+/// `samples` as a 16-bit mono WAV file.
+fn write_wav<W: Write>(out: &mut W, samples: &[i16]) -> io::Result<()> {
+    let data_len = (samples.len() * 2) as u32;
+    let byte_rate = AUDIO_SAMPLE_RATE * 2;
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?; // PCM
+    out.write_all(&1u16.to_le_bytes())?; // mono
+    out.write_all(&AUDIO_SAMPLE_RATE.to_le_bytes())?;
+    out.write_all(&byte_rate.to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?; // block align
+    out.write_all(&16u16.to_le_bytes())?; // bits per sample
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+
+    let mut data = Vec::with_capacity(data_len as usize);
+    for sample in samples {
+        data.extend_from_slice(&sample.to_le_bytes());
+    }
+    out.write_all(&data)
+}
+
+/// Keep client-supplied request ids from escaping the dump directory.
+fn file_safe(request_id: &str) -> String {
+    request_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+struct DumpFiles {
+    stem: String,
+    paths: Vec<PathBuf>,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// Every dump in `dir`, oldest first.
+fn list_dumps(dir: &Path) -> io::Result<Vec<DumpFiles>> {
+    let mut by_stem: HashMap<String, DumpFiles> = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let stem = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(stem) if stem.starts_with(DUMP_PREFIX) => stem.to_string(),
+            _ => continue,
+        };
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let modified = metadata.modified()?;
+
+        let dump = by_stem.entry(stem.clone()).or_insert(DumpFiles {
+            stem: stem,
+            paths: Vec::new(),
+            size: 0,
+            modified: modified,
+        });
+        dump.paths.push(path);
+        dump.size += metadata.len();
+        dump.modified = dump.modified.min(modified);
+    }
+
+    let mut dumps: Vec<DumpFiles> = by_stem.into_values().collect();
+    dumps.sort_by(|a, b| a.modified.cmp(&b.modified).then(a.stem.cmp(&b.stem)));
+    Ok(dumps)
+}
+
+/// Which dumps to delete, oldest first, for the rest to fit `retention`.
+fn expired(dumps: &[DumpFiles], retention: &Retention, now: SystemTime) -> usize {
+    let mut total: u64 = dumps.iter().map(|d| d.size).sum();
+    let mut remove = 0;
+
+    for (i, dump) in dumps.iter().enumerate() {
+        let remaining = dumps.len() - i;
+        let too_many = retention.max_files > 0 && remaining > retention.max_files;
+        let too_big = retention.max_size > 0 && total > retention.max_size;
+        let too_old = retention.max_age > Duration::from_secs(0)
+            && now
+                .duration_since(dump.modified)
+                .map(|age| age > retention.max_age)
+                .unwrap_or(false);
+        if !(too_many || too_big || too_old) {
+            break;
+        }
+        total -= dump.size;
+        remove += 1;
+    }
+    remove
+}
+
+/// Writes requests' audio and results to `dir` for debugging, results being
+/// raw transcripts from before redaction.
+pub struct DebugDumper {
+    dir: PathBuf,
+    mode: DumpMode,
+    retention: Retention,
+    /// Fraction of a request owed to the sample, for `DumpMode::Sampled`
+    sampled: Mutex<f64>,
+    /// Dumps in `dir`, oldest first: listed once, then kept up to date
+    dumps: Mutex<Vec<DumpFiles>>,
+}
+
+impl DebugDumper {
+    pub fn new(dir: &str, mode: DumpMode, retention: Retention) -> Option<DebugDumper> {
+        if mode == DumpMode::Off {
+            return None;
+        }
+        let dumps = list_dumps(Path::new(dir)).unwrap_or_else(|err| {
+            error!("Unable to list dumps in {:?}: {:?}", dir, err);
+            Vec::new()
+        });
+        Some(DebugDumper {
+            dir: PathBuf::from(dir),
+            mode: mode,
+            retention: retention,
+            sampled: Mutex::new(0.0),
+            dumps: Mutex::new(dumps),
+        })
+    }
+
+    fn wanted(&self, result: Option<&InferenceResult>) -> bool {
+        match self.mode {
+            DumpMode::Off => false,
+            DumpMode::All => true,
+            DumpMode::Failures => result.map(|r| !r.is_ok()).unwrap_or(true),
+            DumpMode::Sampled(percent) => {
+                let mut owed = self.sampled.lock().unwrap();
+                *owed += percent / 100.0;
+                if *owed >= 1.0 {
+                    *owed -= 1.0;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    /// Dump `audio` and what became of it, if the mode asks for it.
+    pub fn maybe_dump(
+        &self,
+        request_id: &str,
+        audio: &[u8],
+        result: Option<&InferenceResult>,
+        options: serde_json::Value,
+    ) {
+        if !self.wanted(result) {
+            return;
+        }
+
+        match self.dump(request_id, audio, result, options) {
+            Ok(dump) => {
+                debug!("Dumped request to {:?}", dump.paths[0]);
+                self.enforce_retention(dump);
+            }
+            Err(err) => error!("Unable to dump request: {:?}", err),
+        }
+    }
+
+    fn dump(
+        &self,
+        request_id: &str,
+        audio: &[u8],
+        result: Option<&InferenceResult>,
+        options: serde_json::Value,
+    ) -> io::Result<DumpFiles> {
+        let now = chrono::Utc::now();
+        let stem = format!(
+            "{}{}-{}",
+            DUMP_PREFIX,
+            now.format("%Y%m%dT%H%M%S%.3fZ"),
+            file_safe(request_id)
+        );
+
+        // Audio we could not decode is kept as sent.
+        let audio_path = match decode_audio(audio) {
+            Ok(samples) => {
+                let path = self.dir.join(format!("{}.wav", stem));
+                write_wav(&mut io::BufWriter::new(File::create(&path)?), &samples)?;
+                path
+            }
+            Err(_) => {
+                let path = self.dir.join(format!("{}.raw", stem));
+                File::create(&path)?.write_all(audio)?;
+                path
+            }
+        };
+
+        let sidecar = json!({
+            "request_id": request_id,
+            "timestamp": now.to_rfc3339(),
+            "audio_file": audio_path.file_name().map(|n| n.to_string_lossy().into_owned()),
+            "bytes_in": audio.len(),
+            "options": options,
+            "result": result,
+        });
+        let sidecar_path = self.dir.join(format!("{}.json", stem));
+        let sidecar = serde_json::to_string_pretty(&sidecar).unwrap();
+        File::create(&sidecar_path)?.write_all(sidecar.as_bytes())?;
+
+        let audio_size = fs::metadata(&audio_path)?.len();
+        Ok(DumpFiles {
+            stem: stem,
+            paths: vec![audio_path, sidecar_path],
+            size: audio_size + sidecar.len() as u64,
+            modified: SystemTime::now(),
+        })
+    }
+
+    /// Record `dump` and delete the oldest dumps past the retention limits.
+    fn enforce_retention(&self, dump: DumpFiles) {
+        let mut dumps = self.dumps.lock().unwrap();
+        dumps.push(dump);
+        let remove = expired(&dumps, &self.retention, SystemTime::now());
+        for dump in dumps.drain(..remove) {
+            debug!("Removing old dump {:?}", dump.stem);
+            for path in dump.paths.iter() {
+                match fs::remove_file(path) {
+                    Ok(()) => {}
+                    Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => error!("Unable to remove {:?}: {:?}", path, err),
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_debug_dump() {
+    assert_eq!(parse_mode("25%"), Ok(DumpMode::Sampled(25.0)));
+    assert!(parse_mode("125%").is_err());
+    assert!(parse_mode("sometimes").is_err());
+
+    let dir = ::std::env::temp_dir().join(format!("ds-srv-dump-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let retention = Retention {
+        max_files: 2,
+        max_age: Duration::from_secs(0),
+        max_size: 0,
+    };
+    let dumper = DebugDumper::new(dir.to_str().unwrap(), DumpMode::Sampled(50.0), retention)
+        .unwrap();
+    let mut wav = Vec::new();
+    write_wav(&mut wav, &[0, 1, -1, 0]).unwrap();
+    assert_eq!(wav.len(), 44 + 8);
+    assert_eq!(decode_audio(&wav).unwrap(), vec![0, 1, -1, 0]);
+
+    for i in 0..8 {
+        dumper.maybe_dump(&format!("../req/{}", i), &wav, None, json!({}));
+    }
+
+    let dumps = list_dumps(&dir).unwrap();
+    assert_eq!(dumps.len(), 2);
+    assert!(dumps.iter().all(|d| d.paths.len() == 2 && d.stem.contains("___req_")));
+    assert_eq!(dumper.dumps.lock().unwrap().len(), 2);
+
+    // Dumps left by a previous run count towards the limits
+    let dumper = DebugDumper::new(dir.to_str().unwrap(), DumpMode::All, retention).unwrap();
+    assert_eq!(dumper.dumps.lock().unwrap().len(), 2);
+    dumper.maybe_dump("next", &wav, None, json!({}));
+    assert_eq!(list_dumps(&dir).unwrap().len(), 2);
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,438 @@
+extern crate deepspeech;
+
+use self::deepspeech::Model;
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+use access_log::millis;
+use alignment::{align, render_diff};
+use inference::{decode_audio, inference, start_model};
+use stats::LatencySummary;
+
+#[derive(Debug, Clone, PartialEq)]
+/// Settings of `ds-srv evaluate`
+pub struct EvaluateOptions {
+    /// DeepSpeech CSV files listing audio and reference transcripts
+    pub manifests: Vec<String>,
+    /// Values to try, every combination being one run; empty keeps the
+    /// model's or scorer's own
+    pub alphas: Vec<f32>,
+    pub betas: Vec<f32>,
+    pub beam_widths: Vec<u16>,
+    pub report: Option<String>,
+}
+
+/// Values from `a,b,c` lists and `start:stop:step` ranges (inclusive).
+pub fn parse_grid(grid: &str) -> Result<Vec<f64>, String> {
+    let parse = |value: &str| {
+        value
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|v| v.is_finite())
+            .ok_or_else(|| format!("invalid value {:?}", value))
+    };
+
+    let mut values = Vec::new();
+    for item in grid.split(',') {
+        let range: Vec<&str> = item.split(':').collect();
+        match range.len() {
+            1 => values.push(parse(item)?),
+            3 => {
+                let (start, stop, step) = (parse(range[0])?, parse(range[1])?, parse(range[2])?);
+                if step <= 0.0 || stop < start {
+                    return Err(format!("invalid range {:?}", item));
+                }
+                // Rounded, for 0.1 steps not to drift
+                let steps = ((stop - start) / step + 1e-9).floor() as usize;
+                values.extend(
+                    (0..=steps).map(|i| ((start + step * i as f64) * 1e6).round() / 1e6),
+                );
+            }
+            _ => return Err(format!("expected a value or start:stop:step, got {:?}", item)),
+        }
+    }
+    Ok(values)
+}
+
+/// One line of a manifest
+struct Sample {
+    wav: String,
+    transcript: String,
+    samples: Result<Vec<i16>, String>,
+}
+
+/// Split a CSV line, honouring double quotes.
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            _ => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// Read `wav_filename` and `transcript` columns, paths being relative to
+/// the manifest like for DeepSpeech's training.
+fn read_manifest(path: &str) -> Result<Vec<(String, String)>, String> {
+    let mut content = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .map_err(|e| format!("unable to read {:?}: {}", path, e))?;
+
+    let mut lines = content.lines().filter(|l| !l.trim().is_empty());
+    let header = csv_fields(lines.next().unwrap_or(""));
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|h| h.trim() == name)
+            .ok_or_else(|| format!("{:?} has no {:?} column", path, name))
+    };
+    let (wav_column, transcript_column) = (column("wav_filename")?, column("transcript")?);
+    let base = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+
+    lines
+        .enumerate()
+        .map(|(i, line)| {
+            let fields = csv_fields(line);
+            match (fields.get(wav_column), fields.get(transcript_column)) {
+                (Some(wav), Some(transcript)) => Ok((
+                    base.join(wav.trim()).to_string_lossy().into_owned(),
+                    transcript.trim().to_string(),
+                )),
+                _ => Err(format!("{:?}, line {}: missing columns", path, i + 2)),
+            }
+        })
+        .collect()
+}
+
+fn load_samples(wav: &str) -> Result<Vec<i16>, String> {
+    let mut content = Vec::new();
+    File::open(wav)
+        .and_then(|mut f| f.read_to_end(&mut content))
+        .map_err(|e| format!("unable to read {:?}: {}", wav, e))?;
+    decode_audio(&content)
+        .map_err(|rejected| rejected.error().unwrap_or("invalid audio").to_string())
+}
+
+/// Lowercase words without punctuation, as the model outputs them
+fn normalize(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .map(|w| {
+            w.chars()
+                .filter(|c| c.is_alphanumeric() || *c == '\'')
+                .flat_map(char::to_lowercase)
+                .collect::<String>()
+        })
+        .filter(|w| !w.is_empty())
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+/// Decoder settings of one run, `None` keeping the model's
+pub struct DecoderParams {
+    pub alpha: Option<f32>,
+    pub beta: Option<f32>,
+    pub beam_width: Option<u16>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileResult {
+    pub wav: String,
+    pub reference: String,
+    pub hypothesis: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub word_errors: usize,
+    pub words: usize,
+    pub char_errors: usize,
+    pub chars: usize,
+    /// Reference words edited into the hypothesis, `[-removed-]{+added+}`
+    pub alignment: Option<String>,
+    pub audio_duration_ms: Option<f64>,
+    pub inference_ms: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunReport {
+    pub params: DecoderParams,
+    /// Why the run could not be made, in which case no file was evaluated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub wer: f64,
+    pub cer: f64,
+    /// Inference time over audio duration
+    pub real_time_factor: f64,
+    pub latency_ms: LatencySummary,
+    pub failed: usize,
+    pub files: Vec<FileResult>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EvaluationReport {
+    pub model: String,
+    pub scorer: String,
+    pub manifests: Vec<String>,
+    pub runs: Vec<RunReport>,
+    /// Index in `runs` of the lowest WER
+    pub best: Option<usize>,
+}
+
+fn evaluate_file(model: &mut Model, sample: &Sample) -> FileResult {
+    let reference = normalize(&sample.transcript);
+    let reference_chars: Vec<char> = reference.join(" ").chars().collect();
+    let mut file = FileResult {
+        wav: sample.wav.clone(),
+        reference: sample.transcript.clone(),
+        hypothesis: None,
+        error: None,
+        word_errors: reference.len(),
+        words: reference.len(),
+        char_errors: reference_chars.len(),
+        chars: reference_chars.len(),
+        alignment: None,
+        audio_duration_ms: None,
+        inference_ms: None,
+    };
+
+    let result = match sample.samples {
+        Ok(ref samples) => inference(model, samples),
+        Err(ref err) => {
+            file.error = Some(err.clone());
+            return file;
+        }
+    };
+    file.audio_duration_ms = result.timings.audio_duration.map(millis);
+    file.inference_ms = result.timings.inference_time.map(millis);
+    let text = match result.best() {
+        Some(best) => best.text.clone(),
+        None => {
+            file.error = Some(result.error().unwrap_or("inference failed").to_string());
+            return file;
+        }
+    };
+
+    let hypothesis = normalize(&text);
+    let reference: Vec<&str> = reference.iter().map(|w| w.as_str()).collect();
+    let hypothesis_words: Vec<&str> = hypothesis.iter().map(|w| w.as_str()).collect();
+    let edits = align(&reference, &hypothesis_words);
+    let hypothesis_chars: Vec<char> = hypothesis.join(" ").chars().collect();
+
+    file.word_errors = edits.iter().filter(|e| e.is_error()).count();
+    file.char_errors = align(&reference_chars, &hypothesis_chars)
+        .iter()
+        .filter(|e| e.is_error())
+        .count();
+    file.alignment = Some(render_diff(&edits));
+    file.hypothesis = Some(text);
+    file
+}
+
+fn apply_params(model: &mut Model, params: DecoderParams) -> Result<(), String> {
+    if let (Some(alpha), Some(beta)) = (params.alpha, params.beta) {
+        model
+            .set_scorer_alpha_beta(alpha, beta)
+            .map_err(|e| format!("unable to set alpha={} beta={}: {:?}", alpha, beta, e))?;
+    }
+    if let Some(beam_width) = params.beam_width {
+        model
+            .set_model_beam_width(beam_width)
+            .map_err(|e| format!("unable to set beam width {}: {:?}", beam_width, e))?;
+    }
+    Ok(())
+}
+
+fn evaluate_run(model: &mut Model, params: DecoderParams, samples: &[Sample]) -> RunReport {
+    // Evaluating with whatever settings the previous run left would be misleading
+    let error = apply_params(model, params).err();
+    let files: Vec<FileResult> = match error {
+        Some(ref err) => {
+            error!("Skipping run with {:?}: {}", params, err);
+            Vec::new()
+        }
+        None => samples
+            .iter()
+            .map(|sample| {
+                info!("Evaluating {} with {:?}", sample.wav, params);
+                evaluate_file(model, sample)
+            })
+            .collect(),
+    };
+
+    let ratio = |errors: usize, total: usize| {
+        if total > 0 {
+            errors as f64 / total as f64
+        } else {
+            0.0
+        }
+    };
+    let sum = |f: &dyn Fn(&FileResult) -> usize| files.iter().map(f).sum::<usize>();
+    let latencies: Vec<Duration> = files
+        .iter()
+        .filter_map(|f| f.inference_ms)
+        .map(|ms| Duration::from_micros((ms * 1000.0) as u64))
+        .collect();
+    let audio_ms: f64 = files.iter().filter_map(|f| f.audio_duration_ms).sum();
+    let inference_ms: f64 = files.iter().filter_map(|f| f.inference_ms).sum();
+
+    RunReport {
+        params: params,
+        error: error,
+        wer: ratio(sum(&|f| f.word_errors), sum(&|f| f.words)),
+        cer: ratio(sum(&|f| f.char_errors), sum(&|f| f.chars)),
+        real_time_factor: if audio_ms > 0.0 { inference_ms / audio_ms } else { 0.0 },
+        latency_ms: LatencySummary::new(&latencies),
+        failed: files.iter().filter(|f| f.error.is_some()).count(),
+        files: files,
+    }
+}
+
+/// Every combination of the given decoder settings.
+fn grid(options: &EvaluateOptions) -> Vec<DecoderParams> {
+    let or_none = |values: Vec<Option<f32>>| if values.is_empty() { vec![None] } else { values };
+    let alphas = or_none(options.alphas.iter().cloned().map(Some).collect());
+    let betas = or_none(options.betas.iter().cloned().map(Some).collect());
+    let beam_widths: Vec<Option<u16>> = if options.beam_widths.is_empty() {
+        vec![None]
+    } else {
+        options.beam_widths.iter().cloned().map(Some).collect()
+    };
+
+    let mut params = Vec::new();
+    for &beam_width in beam_widths.iter() {
+        for &alpha in alphas.iter() {
+            for &beta in betas.iter() {
+                params.push(DecoderParams {
+                    alpha: alpha,
+                    beta: beta,
+                    beam_width: beam_width,
+                });
+            }
+        }
+    }
+    params
+}
+
+/// Transcribe every manifest entry with each combination of decoder
+/// settings, and measure errors against the references.
+pub fn run(
+    model: &str,
+    scorer: &str,
+    options: &EvaluateOptions,
+) -> Result<EvaluationReport, String> {
+    let mut samples = Vec::new();
+    for manifest in options.manifests.iter() {
+        for (wav, transcript) in read_manifest(manifest)? {
+            samples.push(Sample {
+                samples: load_samples(&wav),
+                wav: wav,
+                transcript: transcript,
+            });
+        }
+    }
+
+    let mut model_instance = start_model(model.to_string(), scorer.to_string())?;
+    let runs: Vec<RunReport> = grid(options)
+        .into_iter()
+        .map(|params| evaluate_run(&mut model_instance, params, &samples))
+        .collect();
+    let best = (0..runs.len())
+        .filter(|&i| runs[i].error.is_none())
+        .min_by(|&a, &b| runs[a].wer.partial_cmp(&runs[b].wer).unwrap());
+
+    Ok(EvaluationReport {
+        model: model.to_string(),
+        scorer: scorer.to_string(),
+        manifests: options.manifests.clone(),
+        runs: runs,
+        best: best,
+    })
+}
+
+impl EvaluationReport {
+    /// Whether any run could not be made with its decoder settings.
+    pub fn has_failed_runs(&self) -> bool {
+        self.runs.iter().any(|run| run.error.is_some())
+    }
+
+    /// One line per run, for reading in a terminal.
+    pub fn summary(&self) -> String {
+        let show = |value: Option<String>| value.unwrap_or_else(|| "default".to_string());
+        let mut out = String::new();
+        for (i, run) in self.runs.iter().enumerate() {
+            if let Some(ref err) = run.error {
+                out.push_str(&format!(
+                    "alpha={} beta={} beam_width={}: failed, {}\n",
+                    show(run.params.alpha.map(|v| v.to_string())),
+                    show(run.params.beta.map(|v| v.to_string())),
+                    show(run.params.beam_width.map(|v| v.to_string())),
+                    err
+                ));
+                continue;
+            }
+            out.push_str(&format!(
+                "{}alpha={} beta={} beam_width={}: WER {:.2}% CER {:.2}% RTF {:.3} \
+                 latency p50 {:.0}ms p90 {:.0}ms p99 {:.0}ms, {} of {} failed\n",
+                if Some(i) == self.best && self.runs.len() > 1 { "* " } else { "" },
+                show(run.params.alpha.map(|v| v.to_string())),
+                show(run.params.beta.map(|v| v.to_string())),
+                show(run.params.beam_width.map(|v| v.to_string())),
+                run.wer * 100.0,
+                run.cer * 100.0,
+                run.real_time_factor,
+                run.latency_ms.p50,
+                run.latency_ms.p90,
+                run.latency_ms.p99,
+                run.failed,
+                run.files.len()
+            ));
+        }
+        out
+    }
+}
+
+#[test]
+fn test_evaluate_inputs() {
+    assert_eq!(parse_grid("0.5,1.5").unwrap(), vec![0.5, 1.5]);
+    assert_eq!(parse_grid("0.1:0.4:0.1").unwrap(), vec![0.1, 0.2, 0.3, 0.4]);
+    assert_eq!(parse_grid("100:300:100,1024").unwrap(), vec![100.0, 200.0, 300.0, 1024.0]);
+    assert!(parse_grid("1:0:0.5").is_err());
+    assert!(parse_grid("big").is_err());
+
+    assert_eq!(
+        csv_fields(r#"a.wav,123,"hello, ""world""""#),
+        vec!["a.wav", "123", r#"hello, "world""#]
+    );
+
+    assert_eq!(normalize("Don't stop, ok?"), vec!["don't", "stop", "ok"]);
+
+    let options = EvaluateOptions {
+        manifests: Vec::new(),
+        alphas: vec![0.5, 0.75],
+        betas: vec![1.0],
+        beam_widths: vec![100, 500],
+        report: None,
+    };
+    let params = grid(&options);
+    assert_eq!(params.len(), 4);
+    assert_eq!(
+        params[1],
+        DecoderParams {
+            alpha: Some(0.75),
+            beta: Some(1.0),
+            beam_width: Some(100),
+        }
+    );
+}
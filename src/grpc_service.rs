@@ -0,0 +1,563 @@
+extern crate bytes;
+extern crate futures;
+extern crate futures_cpupool;
+
+use self::bytes::Bytes;
+use self::futures::sync::mpsc::{unbounded, UnboundedSender};
+use self::futures::Stream;
+use self::futures_cpupool::CpuPool;
+
+use grpc::protobuf::MarshallerProtobuf;
+use grpc::rt::{
+    GrpcStreaming, MethodDescriptor, MethodHandlerBidi, MethodHandlerUnary, ServerMethod,
+    ServerServiceDefinition,
+};
+use grpc::{
+    Error, GrpcMessageError, GrpcStatus, Metadata, RequestOptions, Server, ServerBuilder,
+    SingleResponse, StreamingRequest, StreamingResponse,
+};
+use protobuf::{Message, RepeatedField};
+
+use std::net::SocketAddr;
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use access_log::{millis, AccessEntry, AccessStats};
+use auth::{ApiKey, AuthError};
+use http::{self, AccessGuard, HttpContext, ResponseFormat, TranscribeOptions};
+use inference::{check_audio_duration, decode_audio, InferenceResult, RawAudioPCM, Rejection};
+use inference::AUDIO_SAMPLE_RATE;
+use lifecycle::{InFlightGuard, Lifecycle};
+use logging::RequestIdGuard;
+use proto::{
+    ListModelsRequest, ListModelsResponse, ModelInfo, RecognitionOptions, RecognitionResult,
+    RecognizeRequest, Redaction, StreamingRecognizeRequest, StreamingRecognizeResponse, Timings,
+    Transcript, WordTiming,
+};
+use rate_limit::RateLimiter;
+use redaction::{self, RedactionOptions};
+use version::VersionInfo;
+
+const SERVICE: &str = "/ds_srv.SpeechToText";
+/// How much more streamed audio, in bytes, before another partial result:
+/// two seconds
+const PARTIAL_RESULT_BYTES: usize = 2 * AUDIO_SAMPLE_RATE as usize * 2;
+
+fn status_error(status: GrpcStatus, message: &str) -> Error {
+    Error::GrpcMessage(GrpcMessageError {
+        grpc_status: status as i32,
+        grpc_message: message.to_string(),
+    })
+}
+
+fn auth_error(err: AuthError) -> Error {
+    match err {
+        AuthError::Unauthorized => {
+            status_error(GrpcStatus::Unauthenticated, "missing or invalid credentials")
+        }
+        AuthError::Forbidden => status_error(GrpcStatus::PermissionDenied, "key is disabled"),
+        AuthError::QuotaExceeded => {
+            status_error(GrpcStatus::ResourceExhausted, "key is over its quota")
+        }
+    }
+}
+
+/// The HTTP status the access log records for a failed call
+fn log_status(err: &Error) -> u16 {
+    let status = match *err {
+        Error::GrpcMessage(ref message) => message.grpc_status,
+        _ => GrpcStatus::Internal as i32,
+    };
+    match status {
+        s if s == GrpcStatus::Unauthenticated as i32 => 401,
+        s if s == GrpcStatus::PermissionDenied as i32 => 403,
+        s if s == GrpcStatus::ResourceExhausted as i32 => 429,
+        s if s == GrpcStatus::Unavailable as i32 => 503,
+        s if s == GrpcStatus::Cancelled as i32 => http::CLIENT_CLOSED_REQUEST,
+        _ => 500,
+    }
+}
+
+/// A transcription, or the error for audio over its key's quota, which
+/// `POST /` answers with `429`
+fn within_quota(result: InferenceResult) -> Result<InferenceResult, Error> {
+    match result.rejection {
+        Some(Rejection::QuotaExceeded) => Err(auth_error(AuthError::QuotaExceeded)),
+        _ => Ok(result),
+    }
+}
+
+fn transcribe_options(options: &RecognitionOptions) -> TranscribeOptions {
+    TranscribeOptions {
+        format: ResponseFormat::Json,
+        redaction: RedactionOptions {
+            mask_profanity: options.get_mask_profanity(),
+            redact_pii: options.get_redact_pii(),
+        },
+        bypass_cache: options.get_bypass_cache(),
+    }
+}
+
+fn redactions(redactions: &[redaction::Redaction]) -> RepeatedField<Redaction> {
+    redactions
+        .iter()
+        .map(|r| {
+            let mut redaction = Redaction::new();
+            redaction.set_kind(r.kind.clone());
+            redaction.set_start(r.start as u32);
+            redaction.set_end(r.end as u32);
+            redaction
+        })
+        .collect()
+}
+
+/// The error for audio over `max_body_size`, like `POST /` answers 413.
+fn too_large(ctx: &HttpContext, size: usize) -> Option<Error> {
+    let max = ctx.max_body_size;
+    if max > 0 && size as u64 > max {
+        Some(status_error(
+            GrpcStatus::ResourceExhausted,
+            &format!("audio is larger than {} bytes", max),
+        ))
+    } else {
+        None
+    }
+}
+
+/// `result` as the `RecognitionResult` message, the JSON of `POST /`.
+fn recognition_result(result: &InferenceResult) -> RecognitionResult {
+    let mut message = RecognitionResult::new();
+    message.set_status(if result.is_ok() { "ok" } else { "ko" }.to_string());
+    message.set_error(result.error().unwrap_or("").to_string());
+    message.set_data(
+        result
+            .data()
+            .iter()
+            .map(|data| {
+                let mut transcript = Transcript::new();
+                transcript.set_text(data.text.clone());
+                transcript.set_formatted_text(data.formatted_text.clone().unwrap_or_default());
+                transcript.set_confidence(data.confidence);
+                transcript.set_words(
+                    data.words
+                        .iter()
+                        .map(|w| {
+                            let mut word = WordTiming::new();
+                            word.set_word(w.word.clone());
+                            word.set_start(w.start);
+                            word.set_end(w.end);
+                            word
+                        })
+                        .collect(),
+                );
+                transcript.set_redactions(redactions(&data.redactions));
+                transcript.set_formatted_redactions(redactions(&data.formatted_redactions));
+                transcript
+            })
+            .collect(),
+    );
+
+    let mut timings = Timings::new();
+    let timing = |d: Option<::std::time::Duration>| d.map(millis).unwrap_or(0.0);
+    timings.set_audio_duration_ms(timing(result.timings.audio_duration));
+    timings.set_queue_wait_ms(timing(result.timings.queue_wait));
+    timings.set_inference_time_ms(timing(result.timings.inference_time));
+    message.set_timings(timings);
+    message
+}
+
+/// The gRPC API, answering with the same queue, cache and settings as
+/// `POST /`
+struct SpeechToText {
+    ctx: Arc<HttpContext>,
+    version: VersionInfo,
+    /// Per API key id, with unauthenticated calls sharing the "" bucket
+    rate_limiter: Option<RateLimiter<String>>,
+    /// Running streams, each on its own thread, and how many may run at once
+    streams: Arc<AtomicUsize>,
+    max_streams: usize,
+    /// Unary calls wait for their result there, off the event loop
+    pool: CpuPool,
+}
+
+/// One of the `max_streams` running streams, given back when dropped
+struct StreamSlot(Arc<AtomicUsize>);
+
+impl StreamSlot {
+    fn take(streams: &Arc<AtomicUsize>, max: usize) -> Option<StreamSlot> {
+        if streams.fetch_add(1, Ordering::SeqCst) < max {
+            Some(StreamSlot(streams.clone()))
+        } else {
+            streams.fetch_sub(1, Ordering::SeqCst);
+            None
+        }
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// What every transcribing call needs before it starts
+struct Call {
+    request_id: String,
+    api_key: Option<Arc<ApiKey>>,
+    in_flight: InFlightGuard,
+}
+
+fn header<'a>(metadata: &'a Metadata, name: &str) -> Option<&'a str> {
+    metadata.get(name).and_then(|v| str::from_utf8(v).ok())
+}
+
+impl SpeechToText {
+    /// The access log line of a call to `method`, written once it ends
+    fn access(&self, method: &str, metadata: &Metadata, request_id: &str) -> AccessGuard {
+        let mut entry = AccessEntry::new(None, request_id);
+        entry.method = "POST".to_string();
+        entry.path = format!("{}/{}", SERVICE, method);
+        entry.version = "HTTP/2.0".to_string();
+        entry.user_agent = header(metadata, "user-agent").map(str::to_string);
+        AccessGuard::new(self.ctx.clone(), entry)
+    }
+
+    /// Rate limit and authenticate a call, and count it in flight, unless we
+    /// are shutting down. Like `POST /`, the rate limit comes first.
+    fn admit(&self, metadata: &Metadata, request_id: String) -> Result<Call, Error> {
+        let api_key = self.ctx.authenticator.as_ref().map(|authenticator| {
+            authenticator.resolve(header(metadata, "x-api-key"), header(metadata, "authorization"))
+        });
+        if let Some(ref limiter) = self.rate_limiter {
+            let client = match api_key {
+                Some(Ok(ref key)) => key.config.id.clone(),
+                _ => String::new(),
+            };
+            if limiter.check(client).is_err() {
+                return Err(status_error(GrpcStatus::ResourceExhausted, "too many requests"));
+            }
+        }
+        let api_key = match api_key {
+            Some(key) => {
+                let key = key.map_err(auth_error)?;
+                key.admit().map_err(auth_error)?;
+                Some(key)
+            }
+            None => None,
+        };
+        let in_flight = Lifecycle::track(&self.ctx.lifecycle)
+            .ok_or_else(|| status_error(GrpcStatus::Unavailable, "shutting down"))?;
+        Ok(Call {
+            request_id: request_id,
+            api_key: api_key,
+            in_flight: in_flight,
+        })
+    }
+
+    fn recognize(
+        &self,
+        o: RequestOptions,
+        mut request: RecognizeRequest,
+    ) -> SingleResponse<RecognitionResult> {
+        let request_id = http::sane_request_id(header(&o.metadata, "x-request-id"));
+        let mut access = self.access("Recognize", &o.metadata, &request_id);
+        let bytes_in = request.get_audio().len();
+        let admitted = self.admit(&o.metadata, request_id).and_then(|call| {
+            match too_large(&self.ctx, bytes_in) {
+                Some(err) => Err(err),
+                None => Ok(call),
+            }
+        });
+        let call = match admitted {
+            Ok(call) => call,
+            Err(err) => {
+                let stats = http::access_stats(bytes_in, None, None);
+                access.log(log_status(&err), None, Some(&stats));
+                return SingleResponse::err(err);
+            }
+        };
+
+        let ctx = self.ctx.clone();
+        SingleResponse::no_metadata(self.pool.spawn_fn(move || {
+            let _in_flight = call.in_flight;
+            let _request_id = RequestIdGuard::new(&call.request_id);
+            info!("gRPC Recognize: {} bytes", bytes_in);
+            let options = transcribe_options(request.get_options());
+            let audio = Bytes::from(request.take_audio());
+            let api_key = call.api_key.as_ref();
+            let recognized = http::recognize(audio, &call.request_id, api_key, &options, &ctx)
+                .result
+                .map_err(|err| status_error(GrpcStatus::Internal, &err))
+                .and_then(within_quota);
+            let stats = http::access_stats(bytes_in, api_key, recognized.as_ref().ok());
+            let message = recognized.map(|result| recognition_result(&result));
+            match message {
+                Ok(ref message) => {
+                    access.log(200, Some(u64::from(message.compute_size())), Some(&stats))
+                }
+                Err(ref err) => access.log(log_status(err), None, Some(&stats)),
+            }
+            message
+        }))
+    }
+
+    fn streaming_recognize(
+        &self,
+        o: RequestOptions,
+        requests: StreamingRequest<StreamingRecognizeRequest>,
+    ) -> StreamingResponse<StreamingRecognizeResponse> {
+        let request_id = http::sane_request_id(header(&o.metadata, "x-request-id"));
+        let mut access = self.access("StreamingRecognize", &o.metadata, &request_id);
+        let admitted = StreamSlot::take(&self.streams, self.max_streams)
+            .ok_or_else(|| status_error(GrpcStatus::ResourceExhausted, "too many streams"))
+            .and_then(|slot| self.admit(&o.metadata, request_id).map(|call| (slot, call)));
+        let (slot, call) = match admitted {
+            Ok(admitted) => admitted,
+            Err(err) => {
+                access.log(log_status(&err), None, None);
+                return StreamingResponse::err(err);
+            }
+        };
+
+        let (tx_response, rx_response) = unbounded();
+        let ctx = self.ctx.clone();
+        let spawned = thread::Builder::new()
+            .name("GrpcStream".to_string())
+            .spawn(move || {
+                let _slot = slot;
+                let _in_flight = call.in_flight;
+                let _request_id = RequestIdGuard::new(&call.request_id);
+                let mut stats = http::access_stats(0, call.api_key.as_ref(), None);
+                let streamed = stream_session(
+                    requests.0.wait(),
+                    &call.request_id,
+                    call.api_key.as_ref(),
+                    &ctx,
+                    &tx_response,
+                    &mut stats,
+                );
+                match streamed {
+                    Ok(()) => access.log(200, None, Some(&stats)),
+                    Err(err) => {
+                        access.log(log_status(&err), None, Some(&stats));
+                        let _ = tx_response.unbounded_send(Err(err));
+                    }
+                }
+            });
+        if let Err(err) = spawned {
+            error!("Unable to start stream thread: {:?}", err);
+            return StreamingResponse::err(status_error(GrpcStatus::Internal, "no thread"));
+        }
+
+        StreamingResponse::no_metadata(
+            rx_response
+                .map_err(|_| status_error(GrpcStatus::Internal, "stream closed"))
+                .and_then(|response| response),
+        )
+    }
+
+    fn list_models(&self, o: RequestOptions, _request: ListModelsRequest) -> ListModelsResponse {
+        let request_id = http::sane_request_id(header(&o.metadata, "x-request-id"));
+        let mut access = self.access("ListModels", &o.metadata, &request_id);
+        let mut model = ModelInfo::new();
+        if let Some(ref identity) = self.version.model {
+            model.set_model(identity.path.clone());
+            model.set_model_sha256(identity.sha256.clone());
+        }
+        if let Some(ref identity) = self.version.scorer {
+            model.set_scorer(identity.path.clone());
+            model.set_scorer_sha256(identity.sha256.clone());
+        }
+        model.set_deepspeech_version(self.version.deepspeech.native_version.clone());
+        model.set_sample_rate(AUDIO_SAMPLE_RATE);
+
+        let mut response = ListModelsResponse::new();
+        response.mut_models().push(model);
+        access.log(200, Some(u64::from(response.compute_size())), None);
+        response
+    }
+}
+
+/// Collect a stream's audio, sending when asked to a partial result of the
+/// audio so far after every `PARTIAL_RESULT_BYTES` of new audio, then the
+/// final result of the whole audio, transcribed like a `POST /` body. Only
+/// the final result is charged to the key. `stats` is what the access log
+/// records of the final result.
+fn stream_session<I>(
+    requests: I,
+    request_id: &str,
+    api_key: Option<&Arc<ApiKey>>,
+    ctx: &HttpContext,
+    tx_response: &UnboundedSender<Result<StreamingRecognizeResponse, Error>>,
+    stats: &mut AccessStats,
+) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<StreamingRecognizeRequest, Error>>,
+{
+    let send = |result: &InferenceResult, is_final: bool| {
+        let mut response = StreamingRecognizeResponse::new();
+        response.set_result(recognition_result(result));
+        response.set_is_final(is_final);
+        tx_response
+            .unbounded_send(Ok(response))
+            .map_err(|_| status_error(GrpcStatus::Cancelled, "client went away"))
+    };
+
+    let mut audio = Vec::new();
+    let mut settings = None;
+    // How much of the audio the last partial result covered
+    let mut partial_end = 0;
+    for request in requests {
+        let mut request = request?;
+        if settings.is_none() {
+            settings = Some((
+                transcribe_options(request.get_options()),
+                request.get_partial_results(),
+            ));
+        }
+        audio.extend_from_slice(&request.take_audio());
+        stats.bytes_in = audio.len() as u64;
+        if let Some(err) = too_large(ctx, audio.len()) {
+            return Err(err);
+        }
+
+        let (ref options, partial_results) = *settings.as_ref().unwrap();
+        if partial_results && audio.len() - partial_end >= PARTIAL_RESULT_BYTES {
+            // Whole samples only, since chunks may split them
+            partial_end = audio.len() & !1;
+            let so_far = &audio[..partial_end];
+            let samples = decode_audio(so_far)
+                .and_then(|samples| {
+                    check_audio_duration(&samples, ctx.max_audio_duration).map(|_| samples)
+                });
+            if let Ok(samples) = samples {
+                let pcm = RawAudioPCM {
+                    content: Bytes::from(so_far),
+                };
+                let mut partial = http::run_inference(request_id, pcm, samples)
+                    .map_err(|err| status_error(GrpcStatus::Internal, &err))?;
+                ctx.redactor.apply(&mut partial, &options.redaction);
+                send(&partial, false)?;
+            }
+        }
+    }
+
+    info!("gRPC StreamingRecognize: {} bytes", audio.len());
+    let options = settings.map(|(options, _)| options).unwrap_or_else(|| {
+        transcribe_options(&RecognitionOptions::new())
+    });
+    let result = http::recognize(Bytes::from(audio), request_id, api_key, &options, ctx)
+        .result
+        .map_err(|err| status_error(GrpcStatus::Internal, &err))
+        .and_then(within_quota)?;
+    *stats = http::access_stats(stats.bytes_in as usize, api_key, Some(&result));
+    send(&result, true)
+}
+
+fn method<Req, Resp>(name: &str, streaming: GrpcStreaming) -> Arc<MethodDescriptor<Req, Resp>>
+where
+    Req: Message + 'static,
+    Resp: Message + 'static,
+{
+    Arc::new(MethodDescriptor {
+        name: format!("{}/{}", SERVICE, name),
+        streaming: streaming,
+        req_marshaller: Box::new(MarshallerProtobuf),
+        resp_marshaller: Box::new(MarshallerProtobuf),
+    })
+}
+
+fn service_definition(service: Arc<SpeechToText>) -> ServerServiceDefinition {
+    let (recognize, streaming, list) = (service.clone(), service.clone(), service);
+    ServerServiceDefinition::new(
+        SERVICE,
+        vec![
+            ServerMethod::new(
+                method("Recognize", GrpcStreaming::Unary),
+                MethodHandlerUnary::new(move |o, request| recognize.recognize(o, request)),
+            ),
+            ServerMethod::new(
+                method("StreamingRecognize", GrpcStreaming::Bidi),
+                MethodHandlerBidi::new(move |o, requests| {
+                    streaming.streaming_recognize(o, requests)
+                }),
+            ),
+            ServerMethod::new(
+                method("ListModels", GrpcStreaming::Unary),
+                MethodHandlerUnary::new(move |o, request| {
+                    SingleResponse::completed(list.list_models(o, request))
+                }),
+            ),
+        ],
+    )
+}
+
+/// Serve the gRPC API on `socket` until the returned server is dropped,
+/// refusing IPv4 connections on IPv6 addresses when `ipv6_only`.
+pub fn start(
+    socket: SocketAddr,
+    ipv6_only: bool,
+    ctx: Arc<HttpContext>,
+    version: VersionInfo,
+    rate_limiter: Option<RateLimiter<String>>,
+    max_streams: usize,
+) -> Result<Server, String> {
+    let mut server = ServerBuilder::new_plain();
+    server.http.conf.only_v6 = Some(ipv6_only);
+    server
+        .http
+        .set_addr(socket)
+        .map_err(|e| format!("Unable to bind gRPC to {}: {}", socket, e))?;
+    server.add_service(service_definition(Arc::new(SpeechToText {
+        ctx: ctx,
+        version: version,
+        rate_limiter: rate_limiter,
+        streams: Arc::new(AtomicUsize::new(0)),
+        max_streams: max_streams,
+        pool: CpuPool::new_num_cpus(),
+    })));
+    let server = server
+        .build()
+        .map_err(|e| format!("Unable to start gRPC on {}: {}", socket, e))?;
+    info!("Listening on grpc://{}", socket);
+    Ok(server)
+}
+
+#[test]
+fn test_recognition_result() {
+    extern crate serde_json;
+
+    let mut result: InferenceResult = serde_json::from_str(
+        r#"{"status": "ok", "data": [{
+            "text": "call me at five", "formatted_text": "Call me at 5.", "confidence": -3.5,
+            "words": [{"word": "call", "start": 0.1, "end": 0.4}],
+            "redactions": [{"kind": "number", "start": 11, "end": 15}]
+        }]}"#,
+    ).unwrap();
+    result.timings.queue_wait = Some(::std::time::Duration::from_millis(12));
+
+    let message = recognition_result(&result);
+    assert_eq!(message.get_status(), "ok");
+    assert_eq!(message.get_error(), "");
+    let transcript = &message.get_data()[0];
+    assert_eq!(transcript.get_formatted_text(), "Call me at 5.");
+    assert_eq!(transcript.get_words()[0].get_word(), "call");
+    assert_eq!(transcript.get_redactions()[0].get_end(), 15);
+    assert!(transcript.get_formatted_redactions().is_empty());
+    assert_eq!(message.get_timings().get_queue_wait_ms(), 12.0);
+    assert_eq!(message.get_timings().get_inference_time_ms(), 0.0);
+}
+
+#[test]
+fn test_stream_slot() {
+    let streams = Arc::new(AtomicUsize::new(0));
+    let first = StreamSlot::take(&streams, 2).unwrap();
+    let _second = StreamSlot::take(&streams, 2).unwrap();
+    assert!(StreamSlot::take(&streams, 2).is_none());
+
+    drop(first);
+    let _third = StreamSlot::take(&streams, 2).unwrap();
+    assert_eq!(streams.load(Ordering::SeqCst), 2);
+}
@@ -1,134 +1,870 @@
+extern crate bytes;
 extern crate futures;
 extern crate hyper;
 extern crate serde_json;
+extern crate tokio;
+extern crate tokio_rustls;
+extern crate uuid;
 
-use args::TcpPort;
-
+use self::bytes::Bytes;
+use self::futures::sync::oneshot;
 use self::futures::{future, Future, Stream};
-use self::hyper::header::{HeaderValue, CONTENT_TYPE};
-use self::hyper::service::service_fn;
+use self::hyper::body::Payload;
+use self::hyper::header::{
+    HeaderValue, ACCEPT, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, ORIGIN,
+    REFERER, RETRY_AFTER, USER_AGENT, WWW_AUTHENTICATE,
+};
+use self::hyper::server::conn::AddrStream;
+use self::hyper::service::{make_service_fn, service_fn};
 use self::hyper::{Body, Method, Request, Response, Server, StatusCode};
+use self::tokio::net::{TcpListener, TcpStream, UnixStream};
+use self::tokio::reactor::Handle;
+use self::tokio::timer::{Delay, Timeout};
+use self::tokio_rustls::server::TlsStream;
+use self::tokio_rustls::TlsAcceptor;
+use self::uuid::Uuid;
 
-use std::net::{IpAddr, SocketAddr};
+use std::io;
+use std::net::SocketAddr;
 use std::sync::mpsc::channel;
 use std::sync::mpsc::Sender;
-
-use std::fs::File;
-use std::io::Read;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 type ResponseFuture = Box<Future<Item = Response<Body>, Error = hyper::Error> + Send>;
 
+use access_log::{millis, AccessEntry, AccessLog, AccessStats};
+use auth::{ApiKey, AuthError, Authenticator};
+use cors::CorsConfig;
+use cache::ResultCache;
+use debug_dump::DebugDumper;
+use inference::InferenceJob;
 use inference::InferenceResult;
+use inference::InferenceTimings;
+use inference::{check_audio_duration, decode_audio, quota_rejected};
 use inference::RawAudioPCM;
+use inference::Rejection;
+use lifecycle::Lifecycle;
+use listen::{bind_tcp, ListenAddr, RouteSet};
+use logging::RequestIdGuard;
+use rate_limit::RateLimiter;
+use redaction::{RedactionOptions, Redactor};
+use subtitles::{self, SubtitleFormat, SubtitleOptions};
+use unix_socket::{self, UnixSocketOptions};
+use version::read_version_file;
+use warmup::WarmupTracker;
+
+static mut tx_audio: Option<Sender<InferenceJob>> = None;
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const REQUEST_ID_MAX_LEN: usize = 128;
+const API_KEY_HEADER: &str = "x-api-key";
+const CACHE_STATUS_HEADER: &str = "x-cache";
+const SERVER_TIMING_HEADER: &str = "server-timing";
+const CACHE_HIT: &str = "HIT";
+const CACHE_MISS: &str = "MISS";
+const CACHE_BYPASS: &str = "BYPASS";
+
+/// State shared by every connection handled by the HTTP listener
+pub struct HttpContext {
+    pub version_json: String,
+    pub version_file: Option<String>,
+    pub lifecycle: Arc<Lifecycle>,
+    pub warmup: Arc<WarmupTracker>,
+    pub access_log: Option<AccessLog>,
+    pub authenticator: Option<Authenticator>,
+    pub rate_limiter: Option<RateLimiter>,
+    pub max_body_size: u64,
+    pub cors: Option<CorsConfig>,
+    pub subtitles: SubtitleOptions,
+    pub redactor: Redactor,
+    pub max_audio_duration: Option<Duration>,
+    pub cache: Option<ResultCache>,
+    pub dumper: Option<DebugDumper>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// How a transcription is sent back to the client
+pub enum ResponseFormat {
+    Json,
+    Text,
+    Subtitles(SubtitleFormat),
+}
+
+fn heartbeat_response(ctx: &HttpContext) -> Response<Body> {
+    let status = if ctx.lifecycle.is_ready() && ctx.warmup.is_done() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Response::builder()
+        .status(status)
+        .body(Body::from(""))
+        .unwrap()
+}
+
+fn warmup_response(ctx: &HttpContext) -> Response<Body> {
+    let body = json!({
+        "ready": ctx.warmup.is_done(),
+        "workers": ctx.warmup.statuses(),
+    });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn version_response(ctx: &HttpContext) -> Response<Body> {
+    let json_version = match ctx.version_file {
+        Some(ref path) => {
+            debug!("Reading version JSON from {:?}", path);
+            match read_version_file(path) {
+                Ok(json) => json,
+                Err(err) => {
+                    error!("Unable to read version file {:?}: {:?}", path, err);
+                    return error_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        &format!("unable to read version file: {}", err),
+                    );
+                }
+            }
+        }
+        None => ctx.version_json.clone(),
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(json_version))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    let error_json = json!({
+        "status": "ko",
+        "error": message,
+    });
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(error_json.to_string()))
+        .unwrap()
+}
+
+/// An error answer to `POST /`, as plain text when the client asked for text
+/// and as JSON otherwise.
+fn format_error(format: ResponseFormat, status: StatusCode, message: &str) -> Response<Body> {
+    match format {
+        ResponseFormat::Text => Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(Body::from(format!("{}\n", message)))
+            .unwrap(),
+        _ => error_response(status, message),
+    }
+}
+
+fn auth_error_response(err: AuthError, format: ResponseFormat) -> Response<Body> {
+    match err {
+        AuthError::Unauthorized => {
+            let mut response = format_error(
+                format,
+                StatusCode::UNAUTHORIZED,
+                "missing or invalid credentials",
+            );
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            response
+        }
+        AuthError::Forbidden => format_error(format, StatusCode::FORBIDDEN, "key is disabled"),
+        AuthError::QuotaExceeded => {
+            format_error(format, StatusCode::TOO_MANY_REQUESTS, "key is over its quota")
+        }
+    }
+}
+
+/// Every key's usage, for admin keys only.
+fn usage_response(req: &Request<Body>, ctx: &HttpContext) -> Response<Body> {
+    let authenticator = match ctx.authenticator {
+        Some(ref authenticator) => authenticator,
+        None => return error_response(StatusCode::NOT_FOUND, "authentication is not enabled"),
+    };
+    let header = |name| req.headers().get(name).and_then(|h| h.to_str().ok());
+    match authenticator.resolve(header(API_KEY_HEADER), header(AUTHORIZATION.as_str())) {
+        Ok(ref key) if key.config.admin => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_string(&authenticator.usage()).unwrap()))
+            .unwrap(),
+        Ok(_) => error_response(StatusCode::FORBIDDEN, "key is not an admin key"),
+        Err(err) => auth_error_response(err, ResponseFormat::Json),
+    }
+}
+
+fn request_id(req: &Request<Body>) -> String {
+    sane_request_id(req.headers().get(REQUEST_ID_HEADER).and_then(|h| h.to_str().ok()))
+}
+
+/// Reuse the caller's request ID when it is sane, or make up a new one.
+pub fn sane_request_id(id: Option<&str>) -> String {
+    match id {
+        Some(id) if !id.is_empty()
+            && id.len() <= REQUEST_ID_MAX_LEN
+            && id.chars().all(|c| c.is_ascii_graphic()) =>
+        {
+            id.to_string()
+        }
+        _ => Uuid::new_v4().to_string(),
+    }
+}
+
+fn header_string(req: &Request<Body>, name: hyper::header::HeaderName) -> Option<String> {
+    req.headers()
+        .get(name)
+        .and_then(|h| h.to_str().ok())
+        .map(String::from)
+}
+
+/// Status logged for requests whose client went away before the reply
+pub const CLIENT_CLOSED_REQUEST: u16 = 499;
+
+/// Writes the access log line of a request exactly once: when it is answered,
+/// fails, or is dropped because the client went away.
+pub struct AccessGuard {
+    ctx: Arc<HttpContext>,
+    entry: Option<AccessEntry>,
+    start: Instant,
+}
+
+impl AccessGuard {
+    pub fn new(ctx: Arc<HttpContext>, entry: AccessEntry) -> AccessGuard {
+        AccessGuard {
+            ctx: ctx,
+            entry: Some(entry),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn log(&mut self, status: u16, bytes_out: Option<u64>, stats: Option<&AccessStats>) {
+        let mut entry = match self.entry.take() {
+            Some(entry) => entry,
+            None => return,
+        };
+        if let Some(ref access_log) = self.ctx.access_log {
+            if let Some(stats) = stats {
+                entry.set_stats(stats);
+            }
+            entry.status = status;
+            entry.bytes_out = bytes_out;
+            entry.duration_ms = millis(self.start.elapsed());
+            access_log.log(&entry);
+        }
+    }
+}
 
-static mut tx_audio: Option<Sender<(RawAudioPCM, Sender<InferenceResult>)>> = None;
+impl Drop for AccessGuard {
+    fn drop(&mut self) {
+        self.log(CLIENT_CLOSED_REQUEST, None, None);
+    }
+}
 
-fn http_handler(req: Request<Body>) -> ResponseFuture {
+fn http_handler(
+    req: Request<Body>,
+    ctx: Arc<HttpContext>,
+    routes: RouteSet,
+    remote_addr: Option<SocketAddr>,
+) -> ResponseFuture {
+    let request_id = request_id(&req);
+    let _request_id = RequestIdGuard::new(&request_id);
     debug!("Received HTTP: {} {}", req.method(), req.uri());
+
+    let mut entry = AccessEntry::new(remote_addr, &request_id);
+    entry.method = req.method().to_string();
+    entry.path = req.uri().path().to_string();
+    entry.version = format!("{:?}", req.version());
+    entry.referer = header_string(&req, REFERER);
+    entry.user_agent = header_string(&req, USER_AGENT);
+    entry.bytes_in = header_string(&req, CONTENT_LENGTH)
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    let mut access = AccessGuard::new(ctx.clone(), entry);
+
+    let header_value = HeaderValue::from_str(&request_id).unwrap();
+    let origin = req.headers().get(ORIGIN).cloned();
+    let ctx_cors = ctx.clone();
+    Box::new(route(req, ctx, routes, request_id, remote_addr).then(move |result| {
+        match result {
+            Ok(mut response) => {
+                response
+                    .headers_mut()
+                    .insert(REQUEST_ID_HEADER, header_value);
+                if let Some(ref cors) = ctx_cors.cors {
+                    cors.apply(origin.as_ref(), &mut response);
+                }
+                access.log(
+                    response.status().as_u16(),
+                    response.body().content_length(),
+                    response.extensions().get::<AccessStats>(),
+                );
+                Ok(response)
+            }
+            Err(err) => {
+                access.log(StatusCode::INTERNAL_SERVER_ERROR.as_u16(), None, None);
+                Err(err)
+            }
+        }
+    }))
+}
+
+fn query_param<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .filter_map(|pair| {
+                let mut kv = pair.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(key), Some(value)) if key == name => Some(value),
+                    _ => None,
+                }
+            })
+            .next()
+    })
+}
+
+fn query_flag(req: &Request<Body>, name: &str) -> Result<bool, String> {
+    match query_param(req, name) {
+        None | Some("false") | Some("0") => Ok(false),
+        Some("true") | Some("1") => Ok(true),
+        Some(value) => Err(format!("invalid {} {:?}, expected true or false", name, value)),
+    }
+}
+
+fn redaction_options(req: &Request<Body>) -> Result<RedactionOptions, String> {
+    Ok(RedactionOptions {
+        mask_profanity: query_flag(req, "mask_profanity")?,
+        redact_pii: query_flag(req, "redact_pii")?,
+    })
+}
+
+pub fn format_by_name(name: &str) -> Option<ResponseFormat> {
+    match name.to_lowercase().as_str() {
+        "json" => Some(ResponseFormat::Json),
+        "text" | "txt" => Some(ResponseFormat::Text),
+        "srt" => Some(ResponseFormat::Subtitles(SubtitleFormat::Srt)),
+        "vtt" | "webvtt" => Some(ResponseFormat::Subtitles(SubtitleFormat::WebVtt)),
+        _ => None,
+    }
+}
+
+fn format_by_media_type(media_type: &str) -> Option<ResponseFormat> {
+    match media_type.to_lowercase().as_str() {
+        "application/json" | "*/*" => Some(ResponseFormat::Json),
+        "text/plain" => Some(ResponseFormat::Text),
+        "application/x-subrip" | "text/srt" => Some(ResponseFormat::Subtitles(SubtitleFormat::Srt)),
+        "text/vtt" => Some(ResponseFormat::Subtitles(SubtitleFormat::WebVtt)),
+        _ => None,
+    }
+}
+
+/// Pick the transcription format from the `format` query parameter, or else
+/// the first media type of the `Accept` header we know, defaulting to JSON.
+fn response_format(req: &Request<Body>) -> Result<ResponseFormat, String> {
+    if let Some(name) = query_param(req, "format") {
+        return format_by_name(name).ok_or_else(|| {
+            format!("unknown format {:?}, expected json, text, srt or vtt", name)
+        });
+    }
+
+    let accept = req.headers().get(ACCEPT).and_then(|h| h.to_str().ok()).unwrap_or("");
+    Ok(accept
+        .split(',')
+        .filter_map(|item| item.split(';').next())
+        .filter_map(|media_type| format_by_media_type(media_type.trim()))
+        .next()
+        .unwrap_or(ResponseFormat::Json))
+}
+
+/// `413` and `422` for refused audio, `500` when inference failed.
+fn transcription_status(result: &InferenceResult) -> StatusCode {
+    match result.rejection {
+        Some(Rejection::AudioTooLong) => StatusCode::PAYLOAD_TOO_LARGE,
+        Some(Rejection::InvalidAudio) => StatusCode::UNPROCESSABLE_ENTITY,
+        Some(Rejection::QuotaExceeded) => StatusCode::TOO_MANY_REQUESTS,
+        None if result.is_ok() => StatusCode::OK,
+        None => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Serialize an inference in the format the client asked for. Failures are
+/// reported as plain text in plain text mode and as JSON otherwise.
+fn transcription_response(
+    result: &InferenceResult,
+    format: ResponseFormat,
+    options: &SubtitleOptions,
+) -> Response<Body> {
+    let status = transcription_status(result);
+    match (format, result.best()) {
+        (ResponseFormat::Text, Some(best)) => Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(Body::from(format!("{}\n", best.display_text())))
+            .unwrap(),
+        (ResponseFormat::Text, None) => {
+            let status = if status.is_success() {
+                StatusCode::INTERNAL_SERVER_ERROR
+            } else {
+                status
+            };
+            format_error(format, status, result.error().unwrap_or("inference failed"))
+        }
+        (ResponseFormat::Subtitles(subtitle_format), Some(best)) => {
+            let cues = subtitles::cues(&best.words, options);
+            Response::builder()
+                .status(status)
+                .header(CONTENT_TYPE, subtitle_format.content_type())
+                .body(Body::from(subtitles::render(subtitle_format, &cues)))
+                .unwrap()
+        }
+        _ => Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_string(result).unwrap()))
+            .unwrap(),
+    }
+}
+
+enum BodyError {
+    TooLarge,
+    Hyper(hyper::Error),
+}
+
+/// Buffer the request body, giving up as soon as it grows past `max_size`
+/// bytes (0 meaning no limit).
+fn read_body(
+    body: Body,
+    max_size: u64,
+) -> Box<dyn Future<Item = Option<Bytes>, Error = hyper::Error> + Send> {
+    Box::new(
+        body.map_err(BodyError::Hyper)
+            .fold(Vec::new(), move |mut acc, chunk| {
+                if max_size > 0 && (acc.len() + chunk.len()) as u64 > max_size {
+                    return Err(BodyError::TooLarge);
+                }
+                acc.extend_from_slice(&chunk);
+                Ok(acc)
+            })
+            .then(|rv| match rv {
+                Ok(content) => Ok(Some(Bytes::from(content))),
+                Err(BodyError::TooLarge) => Ok(None),
+                Err(BodyError::Hyper(err)) => Err(err),
+            }),
+    )
+}
+
+fn body_too_large(max_size: u64, format: ResponseFormat) -> Response<Body> {
+    format_error(
+        format,
+        StatusCode::PAYLOAD_TOO_LARGE,
+        &format!("request body is larger than {} bytes", max_size),
+    )
+}
+
+/// What a client asked for besides the transcription itself
+pub struct TranscribeOptions {
+    pub format: ResponseFormat,
+    pub redaction: RedactionOptions,
+    /// Ignore cached results, as asked with `Cache-Control: no-cache`
+    pub bypass_cache: bool,
+}
+
+/// Where `run_inference` queues audio, before any request comes in.
+pub fn set_inference_queue(queue: Sender<InferenceJob>) {
+    unsafe {
+        tx_audio = Some(queue);
+    }
+}
+
+/// Queue audio for the inference thread and wait for its result.
+pub fn run_inference(
+    request_id: &str,
+    pcm: RawAudioPCM,
+    samples: Vec<i16>,
+) -> Result<InferenceResult, String> {
+    let infer = format!("inference: {}", pcm.content.len());
+    let (tx_string, rx_string) = channel();
+
+    unsafe {
+        match tx_audio {
+            Some(ref tx_audio_ok) => match tx_audio_ok.clone().send(InferenceJob {
+                request_id: request_id.to_string(),
+                audio: pcm,
+                samples: samples,
+                tx_result: tx_string,
+                queued_at: Instant::now(),
+            }) {
+                Ok(_) => {
+                    debug!("Successfully sent message to thread");
+                    match rx_string.recv() {
+                        Ok(decoded_audio) => {
+                            // Transcripts may be sensitive, keep them out of logs
+                            info!(
+                                "Received reply: {}, {} characters",
+                                if decoded_audio.is_ok() { "ok" } else { "ko" },
+                                decoded_audio.best().map(|best| best.text.len()).unwrap_or(0)
+                            );
+                            Ok(decoded_audio)
+                        }
+                        Err(err_recv) => {
+                            error!("Error trying to rx.recv(): {:?}", err_recv);
+                            Err(infer)
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!("Error while sending message to thread: {:?}", err);
+                    Err(infer)
+                }
+            },
+            None => {
+                error!("Unable to tx.send()");
+                Err(infer)
+            }
+        }
+    }
+}
+
+/// A transcription, and whether it came from the cache
+pub struct Recognized {
+    /// Why inference could not run, when it could not
+    pub result: Result<InferenceResult, String>,
+    pub cache_status: Option<&'static str>,
+}
+
+/// Decode and check audio, then answer from the cache or the model, dumping
+/// and redacting the result as configured. Shared by `POST /` and gRPC.
+pub fn recognize(
+    raw_pcm: Bytes,
+    request_id: &str,
+    api_key: Option<&Arc<ApiKey>>,
+    options: &TranscribeOptions,
+    ctx: &HttpContext,
+) -> Recognized {
+    let sent = raw_pcm.clone();
+    let audio = decode_audio(&raw_pcm)
+        .and_then(|samples| {
+            check_audio_duration(&samples, ctx.max_audio_duration)
+                .map(|duration| (samples, duration))
+        })
+        // Charged up front, so that no request runs past the key's quota
+        .and_then(|(samples, duration)| match api_key {
+            Some(key) => key
+                .charge_audio(duration)
+                .map(|_| (samples, duration))
+                .map_err(|_| quota_rejected(duration)),
+            None => Ok((samples, duration)),
+        });
+    let mut cache_status = None;
+    let result = match audio {
+        Err(rejected) => Ok(rejected),
+        Ok((samples, duration)) => {
+            let cached = match ctx.cache {
+                Some(ref cache) => {
+                    let key = cache.key(&samples);
+                    let hit = if options.bypass_cache {
+                        None
+                    } else {
+                        cache.get(&key)
+                    };
+                    cache_status = Some(match hit {
+                        Some(_) => CACHE_HIT,
+                        None if options.bypass_cache => CACHE_BYPASS,
+                        None => CACHE_MISS,
+                    });
+                    hit.ok_or(Some((cache, key)))
+                }
+                None => Err(None),
+            };
+
+            match cached {
+                Ok(mut hit) => {
+                    debug!("Answering from cache");
+                    hit.timings = InferenceTimings::default();
+                    hit.timings.audio_duration = Some(duration);
+                    Ok(hit)
+                }
+                Err(store) => {
+                    let rv = run_inference(request_id, RawAudioPCM { content: raw_pcm }, samples);
+                    if let (Ok(ref result), Some((cache, key))) = (&rv, store) {
+                        cache.put(&key, result);
+                    }
+                    rv
+                }
+            }
+        }
+    };
+
+    if let Some(ref dumper) = ctx.dumper {
+        let dumped_options = json!({
+            "format": format!("{:?}", options.format),
+            "mask_profanity": options.redaction.mask_profanity,
+            "redact_pii": options.redaction.redact_pii,
+            "bypass_cache": options.bypass_cache,
+        });
+        dumper.maybe_dump(request_id, &sent, result.as_ref().ok(), dumped_options);
+    }
+
+    let result = result.map(|mut result| {
+        ctx.redactor.apply(&mut result, &options.redaction);
+        result
+    });
+    Recognized {
+        result: result,
+        cache_status: cache_status,
+    }
+}
+
+/// What the access log records of a transcription.
+pub fn access_stats(
+    bytes_in: usize,
+    api_key: Option<&Arc<ApiKey>>,
+    result: Option<&InferenceResult>,
+) -> AccessStats {
+    let mut stats = AccessStats {
+        bytes_in: bytes_in as u64,
+        api_key: api_key.map(|k| k.config.id.clone()),
+        ..AccessStats::default()
+    };
+    if let Some(result) = result {
+        stats.audio_duration = result.timings.audio_duration;
+        stats.queue_wait = result.timings.queue_wait;
+        stats.inference_time = result.timings.inference_time;
+    }
+    stats
+}
+
+/// Transcribe a `POST /` body and build the response.
+fn transcribe(
+    raw_pcm: Bytes,
+    request_id: String,
+    api_key: Option<Arc<ApiKey>>,
+    options: TranscribeOptions,
+    ctx: &HttpContext,
+) -> Response<Body> {
+    debug!("RAW PCM is {:?} bytes", raw_pcm.len());
+    let bytes_in = raw_pcm.len();
+    let recognized = recognize(raw_pcm, &request_id, api_key.as_ref(), &options, ctx);
+    let stats = access_stats(bytes_in, api_key.as_ref(), recognized.result.as_ref().ok());
+    let mut response = match recognized.result {
+        Ok(decoded_audio) => {
+            transcription_response(&decoded_audio, options.format, &ctx.subtitles)
+        }
+        Err(err) => format_error(options.format, StatusCode::INTERNAL_SERVER_ERROR, &err),
+    };
+
+    if let Some(cache_status) = recognized.cache_status {
+        response
+            .headers_mut()
+            .insert(CACHE_STATUS_HEADER, HeaderValue::from_static(cache_status));
+    }
+    if let Some(timing) = server_timing(&stats) {
+        response
+            .headers_mut()
+            .insert(SERVER_TIMING_HEADER, HeaderValue::from_str(&timing).unwrap());
+    }
+    response.extensions_mut().insert(stats);
+    response
+}
+
+/// Where a transcription's time went, as a `Server-Timing` header value.
+fn server_timing(stats: &AccessStats) -> Option<String> {
+    let metrics: Vec<String> = [
+        ("queue", stats.queue_wait),
+        ("inference", stats.inference_time),
+        ("audio", stats.audio_duration),
+    ].iter()
+        .filter_map(|&(name, duration)| duration.map(|d| format!("{};dur={:.1}", name, millis(d))))
+        .collect();
+    if metrics.is_empty() {
+        None
+    } else {
+        Some(metrics.join(", "))
+    }
+}
+
+fn cache_response(ctx: &HttpContext) -> Response<Body> {
+    match ctx.cache {
+        Some(ref cache) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_string(&cache.stats()).unwrap()))
+            .unwrap(),
+        None => error_response(StatusCode::NOT_FOUND, "result cache is not enabled"),
+    }
+}
+
+/// Whether the client asked for a fresh transcription with `Cache-Control`.
+fn no_cache(req: &Request<Body>) -> bool {
+    req.headers()
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-cache"))
+}
+
+fn route(
+    req: Request<Body>,
+    ctx: Arc<HttpContext>,
+    routes: RouteSet,
+    request_id: String,
+    remote_addr: Option<SocketAddr>,
+) -> ResponseFuture {
+    if !routes.allows(req.uri().path()) {
+        debug!("Not served on this listener");
+        return Box::new(future::ok(
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap(),
+        ));
+    }
     match (req.method(), req.uri().path()) {
         (&Method::GET, "/__version__") => {
-            debug!("Reading version JSON from /app/version.json");
-            let mut json_version = String::new();
-            let mut file = File::open("/app/version.json").unwrap();
-            file.read_to_string(&mut json_version).unwrap();
-            Box::new(future::ok(
-                Response::builder()
-                    .status(StatusCode::OK)
-                    .header(CONTENT_TYPE, "application/json")
-                    .body(Body::from(json_version))
-                    .unwrap()
-            ))
+            Box::new(future::ok(version_response(&ctx)))
         },
         (&Method::GET, "/__heartbeat__") => {
             debug!("App heatbeat checks");
-            Box::new(future::ok(
-                Response::builder()
-                    .status(StatusCode::OK)
-                    .body(Body::from(""))
-                    .unwrap()
-            ))
+            Box::new(future::ok(heartbeat_response(&ctx)))
         },
         (&Method::GET, "/__lbheartbeat__") => {
             debug!("Load-Balancer heatbeat checks");
-            Box::new(future::ok(
-                Response::builder()
-                    .status(StatusCode::OK)
-                    .body(Body::from(""))
-                    .unwrap()
-            ))
+            Box::new(future::ok(heartbeat_response(&ctx)))
+        },
+        (&Method::GET, "/__warmup__") => {
+            debug!("Warmup status");
+            Box::new(future::ok(warmup_response(&ctx)))
+        },
+        (&Method::GET, "/__usage__") => {
+            debug!("API key usage");
+            Box::new(future::ok(usage_response(&req, &ctx)))
+        },
+        (&Method::GET, "/__cache__") => {
+            debug!("Result cache stats");
+            Box::new(future::ok(cache_response(&ctx)))
+        },
+        (&Method::OPTIONS, _) if ctx.cors.is_some() => {
+            debug!("CORS preflight");
+            let cors = ctx.cors.as_ref().unwrap();
+            Box::new(future::ok(cors.preflight(req.headers())))
         },
         (&Method::POST, "/") => {
+            // Errors use the format asked for, or JSON if that is not valid
+            let error_format = response_format(&req).unwrap_or(ResponseFormat::Json);
+            if let (Some(ref limiter), Some(addr)) = (&ctx.rate_limiter, remote_addr) {
+                if let Err(wait) = limiter.check(addr.ip()) {
+                    info!("Rate limiting {}", addr.ip());
+                    let mut response = format_error(
+                        error_format,
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "too many requests",
+                    );
+                    let retry_after = wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 };
+                    response
+                        .headers_mut()
+                        .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+                    return Box::new(future::ok(response));
+                }
+            }
+            let api_key = match ctx.authenticator {
+                Some(ref authenticator) => {
+                    let header = |name| req.headers().get(name).and_then(|h| h.to_str().ok());
+                    match authenticator
+                        .authenticate(header(API_KEY_HEADER), header(AUTHORIZATION.as_str()))
+                    {
+                        Ok(key) => {
+                            debug!("Authenticated as {:?}", key.config.id);
+                            Some(key)
+                        }
+                        Err(err) => {
+                            info!("Refusing POST: {:?}", err);
+                            return Box::new(future::ok(auth_error_response(err, error_format)));
+                        }
+                    }
+                }
+                None => None,
+            };
+            let in_flight = match Lifecycle::track(&ctx.lifecycle) {
+                Some(guard) => guard,
+                None => {
+                    info!("Shutting down, rejecting POST");
+                    return Box::new(future::ok(format_error(
+                        error_format,
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "shutting down",
+                    )));
+                }
+            };
             debug!("POST connection accepted");
+            let options = response_format(&req)
+                .and_then(|format| redaction_options(&req).map(|redaction| (format, redaction)));
+            let options = match options {
+                Ok((format, redaction)) => TranscribeOptions {
+                    format: format,
+                    redaction: redaction,
+                    bypass_cache: no_cache(&req),
+                },
+                Err(err) => {
+                    info!("Refusing POST: {}", err);
+                    return Box::new(future::ok(format_error(
+                        error_format,
+                        StatusCode::BAD_REQUEST,
+                        &err,
+                    )));
+                }
+            };
             let (parts, body) = req.into_parts();
             match parts.headers.get(CONTENT_TYPE) {
                 Some(h) if h == HeaderValue::from_static("application/octet-stream") => {
                     debug!("This is valid: {:?}", h);
-                    Box::new(body.concat2().map(|audio_content| {
-                        let raw_pcm = audio_content.into_bytes();
-                        debug!("RAW PCM is {:?} bytes", raw_pcm.len());
-                        let inference_result = raw_pcm.len();
-                        let infer = format!("inference: {}", inference_result);
-
-                        let pcm = RawAudioPCM {
-                            content: raw_pcm.clone(),
-                        };
-
-                        let (tx_string, rx_string) = channel();
-
-                        unsafe {
-                            match tx_audio {
-                                Some(ref tx_audio_ok) => match tx_audio_ok
-                                    .clone()
-                                    .send((pcm, tx_string))
-                                {
-                                    Ok(_) => {
-                                        debug!("Successfully sent message to thread");
-                                        match rx_string.recv() {
-                                            Ok(decoded_audio) => {
-                                                info!("Received reply: {:?}", decoded_audio);
-                                                Response::builder()
-                                                    .status(StatusCode::OK)
-                                                    .header(CONTENT_TYPE, "application/json")
-                                                    .body(Body::from(
-                                                        serde_json::to_string(&decoded_audio)
-                                                            .unwrap(),
-                                                    ))
-                                                    .unwrap()
-                                            }
-                                            Err(err_recv) => {
-                                                error!("Error trying to rx.recv(): {:?}", err_recv);
-                                                Response::builder()
-                                                    .status(StatusCode::NOT_FOUND)
-                                                    .body(infer.into())
-                                                    .unwrap()
-                                            }
-                                        }
-                                    }
-                                    Err(err) => {
-                                        error!("Error while sending message to thread: {:?}", err);
-                                        Response::builder()
-                                            .status(StatusCode::NOT_FOUND)
-                                            .body(infer.into())
-                                            .unwrap()
-                                    }
-                                },
-                                None => {
-                                    error!("Unable to tx.send()");
-                                    Response::builder()
-                                        .status(StatusCode::NOT_FOUND)
-                                        .body(infer.into())
-                                        .unwrap()
-                                }
+                    let max_body_size = ctx.max_body_size;
+                    let content_length = parts
+                        .headers
+                        .get(CONTENT_LENGTH)
+                        .and_then(|h| h.to_str().ok())
+                        .and_then(|h| h.parse::<u64>().ok());
+                    match content_length {
+                        Some(len) if max_body_size > 0 && len > max_body_size => {
+                            info!("Refusing {} bytes body", len);
+                            return Box::new(future::ok(body_too_large(
+                                max_body_size,
+                                options.format,
+                            )));
+                        }
+                        _ => {}
+                    }
+
+                    Box::new(read_body(body, max_body_size).map(move |audio_content| {
+                        let _in_flight = in_flight;
+                        let _request_id = RequestIdGuard::new(&request_id);
+                        match audio_content {
+                            Some(raw_pcm) => {
+                                transcribe(raw_pcm, request_id, api_key, options, &ctx)
+                            }
+                            None => {
+                                info!("Body grew past {} bytes", max_body_size);
+                                body_too_large(max_body_size, options.format)
                             }
                         }
                     }))
                 }
-                _ => Box::new(future::ok(
-                    Response::builder()
-                        .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
-                        .body(Body::empty())
-                        .unwrap(),
-                )),
+                _ => Box::new(future::ok(format_error(
+                    options.format,
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "expected Content-Type: application/octet-stream",
+                ))),
             }
         }
         _ => Box::new(future::ok(
@@ -140,20 +876,156 @@ fn http_handler(req: Request<Body>) -> ResponseFuture {
     }
 }
 
+/// Complete TLS handshakes concurrently, dropping connections that fail
+/// them instead of stopping the listener.
+const TLS_MAX_PENDING_HANDSHAKES: usize = 64;
+
+/// Drop clients that have not completed their TLS handshake by then.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Pause accepting after an error such as `EMFILE`, rather than spinning.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+type ServerFuture = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+/// Resolves once asked to shut down, for every server to stop accepting
+/// connections then.
+fn on_shutdown(shutdown: &future::Shared<oneshot::Receiver<()>>) -> impl Future<Item = ()> {
+    shutdown.clone().then(|_| Ok::<(), ()>(()))
+}
+
+fn tcp_server(
+    listen: ListenAddr,
+    ipv6_only: bool,
+    ctx: Arc<HttpContext>,
+    tls: Option<TlsAcceptor>,
+    shutdown: &future::Shared<oneshot::Receiver<()>>,
+) -> Result<ServerFuture, String> {
+    let socket = listen.addr;
+    let routes = listen.routes;
+    let listener = bind_tcp(socket, ipv6_only)
+        .map_err(|e| format!("Unable to bind {}: {}", socket, e))?;
+    Ok(match tls {
+        None => Box::new(
+            Server::from_tcp(listener)
+                .map_err(|e| format!("Unable to listen on {}: {}", socket, e))?
+                .serve(make_service_fn(move |conn: &AddrStream| {
+                    let ctx = ctx.clone();
+                    let remote_addr = conn.remote_addr();
+                    service_fn(move |req| {
+                        http_handler(req, ctx.clone(), routes, Some(remote_addr))
+                    })
+                }))
+                .with_graceful_shutdown(on_shutdown(shutdown))
+                .map_err(move |e| error!("Server on {} failed: {}", socket, e)),
+        ),
+        Some(acceptor) => {
+            let incoming = TcpListener::from_std(listener, &Handle::default())
+                .map_err(|e| format!("Unable to listen on {}: {}", socket, e))?
+                .incoming()
+                .then(move |rv| match rv {
+                    Ok(stream) => future::Either::A(future::ok(Some(stream))),
+                    Err(err) => {
+                        warn!("Unable to accept a connection on {}: {}", socket, err);
+                        let resume = Instant::now() + ACCEPT_ERROR_BACKOFF;
+                        future::Either::B(Delay::new(resume).then(|_| Ok(None)))
+                    }
+                })
+                .filter_map(|stream| stream)
+                .map(move |stream| {
+                    Timeout::new(acceptor.accept(stream), TLS_HANDSHAKE_TIMEOUT).then(|rv| {
+                        match rv {
+                            Ok(tls_stream) => Ok::<_, io::Error>(Some(tls_stream)),
+                            Err(err) => {
+                                debug!("TLS handshake failed: {:?}", err);
+                                Ok(None)
+                            }
+                        }
+                    })
+                })
+                .buffer_unordered(TLS_MAX_PENDING_HANDSHAKES)
+                .filter_map(|tls_stream| tls_stream);
+            Box::new(
+                Server::builder(incoming)
+                    .serve(make_service_fn(move |conn: &TlsStream<TcpStream>| {
+                        let ctx = ctx.clone();
+                        let remote_addr = conn.get_ref().0.peer_addr().ok();
+                        service_fn(move |req| http_handler(req, ctx.clone(), routes, remote_addr))
+                    }))
+                    .with_graceful_shutdown(on_shutdown(shutdown))
+                    .map_err(move |e| error!("Server on {} failed: {}", socket, e)),
+            )
+        }
+    })
+}
+
+/// Plain HTTP on a Unix domain socket, whose clients have no address to rate
+/// limit by.
+fn unix_server(
+    options: &UnixSocketOptions,
+    ctx: Arc<HttpContext>,
+    shutdown: &future::Shared<oneshot::Receiver<()>>,
+) -> Result<ServerFuture, String> {
+    let (listener, socket_file) = unix_socket::bind(options)?;
+    let path = options.path.clone();
+    Ok(Box::new(
+        Server::builder(listener.incoming())
+            .serve(make_service_fn(move |_: &UnixStream| {
+                let ctx = ctx.clone();
+                service_fn(move |req| http_handler(req, ctx.clone(), RouteSet::ALL, None))
+            }))
+            .with_graceful_shutdown(on_shutdown(shutdown))
+            .map_err(move |e| error!("Server on {} failed: {}", path, e))
+            // The socket file goes with the server
+            .then(move |result| {
+                drop(socket_file);
+                result
+            }),
+    ))
+}
+
+/// Serve HTTP over TCP on `listeners`, over TLS when given an acceptor, and
+/// on the Unix domain socket `unix_socket`, until `rx_shutdown` fires.
 pub fn th_http_listener(
-    http_ip: IpAddr,
-    http_port: TcpPort,
-    _tx_audio: Sender<(RawAudioPCM, Sender<InferenceResult>)>,
+    listeners: Vec<ListenAddr>,
+    ipv6_only: bool,
+    unix_socket: Option<UnixSocketOptions>,
+    ctx: Arc<HttpContext>,
+    tls: Option<TlsAcceptor>,
+    rx_shutdown: oneshot::Receiver<()>,
+    tx_listening: Sender<()>,
 ) {
-    unsafe {
-        tx_audio = Some(_tx_audio);
+    let shutdown = rx_shutdown.shared();
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let mut servers = Vec::new();
+    let mut endpoints = Vec::new();
+    for listen in listeners {
+        info!("Building server {}://{}", scheme, listen.addr);
+        servers.push(tcp_server(listen, ipv6_only, ctx.clone(), tls.clone(), &shutdown));
+        endpoints.push(format!("{}://{}", scheme, listen.addr));
+    }
+    if let Some(ref options) = unix_socket {
+        info!("Building server on socket {}", options.path);
+        servers.push(unix_server(options, ctx.clone(), &shutdown));
+        endpoints.push(format!("unix:{}", options.path));
     }
+    let servers = match servers.into_iter().collect::<Result<Vec<_>, _>>() {
+        Ok(servers) => servers,
+        Err(err) => {
+            error!("{}", err);
+            return;
+        }
+    };
 
-    let socket = SocketAddr::new(http_ip, http_port);
-    info!("Building server http://{}", &socket);
-    let server = Server::bind(&socket)
-        .serve(|| service_fn(http_handler))
-        .map_err(|e| eprintln!("server error: {}", e));
-    info!("Listening on http://{}", socket);
-    hyper::rt::run(server);
+    info!("Listening on {}", endpoints.join(" and "));
+    // Nobody may be waiting
+    let _ = tx_listening.send(());
+    hyper::rt::run(future::lazy(move || {
+        // Each on its own, so that one failing leaves the others serving
+        for server in servers {
+            hyper::rt::spawn(server);
+        }
+        Ok(())
+    }));
+    info!("HTTP listener stopped");
 }
@@ -4,8 +4,6 @@ extern crate audrey;
 extern crate deepspeech;
 extern crate futures;
 
-extern crate mkstemp;
-
 extern crate byte_slice_cast;
 extern crate bytes;
 
@@ -16,46 +14,151 @@ use self::byte_slice_cast::*;
 use self::bytes::Bytes;
 use self::deepspeech::Model;
 
-use std::fs::File;
 use std::io::Cursor;
 use std::path::Path;
+use std::process;
 use std::sync::mpsc::{Receiver, Sender};
-use std::time::Instant;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
+use logging::RequestIdGuard;
+use postprocess::Postprocessor;
+use redaction::Redaction;
+use warmup::{self, WarmupOptions, WarmupTracker};
+
 #[derive(Debug)]
 pub struct RawAudioPCM {
     pub content: Bytes,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug)]
+/// Audio queued for inference, with where to send the result
+pub struct InferenceJob {
+    pub request_id: String,
+    pub audio: RawAudioPCM,
+    /// `audio` decoded to 16kHz mono samples
+    pub samples: Vec<i16>,
+    pub tx_result: Sender<InferenceResult>,
+    pub queued_at: Instant,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A transcribed word and when it is spoken, in seconds from the start
+pub struct WordTiming {
+    pub word: String,
+    pub start: f32,
+    pub end: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InferenceData {
-    text: String,
-    confidence: f32,
+    pub text: String,
+    /// `text` after post-processing, when enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub formatted_text: Option<String>,
+    pub confidence: f32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub words: Vec<WordTiming>,
+    /// Masked parts of `text`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redactions: Vec<Redaction>,
+    /// Masked parts of `formatted_text`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub formatted_redactions: Vec<Redaction>,
+}
+
+impl InferenceData {
+    /// The formatted transcription if there is one, the raw one otherwise.
+    pub fn display_text(&self) -> &str {
+        self.formatted_text.as_ref().unwrap_or(&self.text)
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default)]
+/// How long a request spent in the inference thread
+pub struct InferenceTimings {
+    pub audio_duration: Option<Duration>,
+    pub queue_wait: Option<Duration>,
+    pub inference_time: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Why audio was refused without running inference
+pub enum Rejection {
+    AudioTooLong,
+    InvalidAudio,
+    /// The audio would take the API key over its daily audio quota
+    QuotaExceeded,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InferenceResult {
     status: String,
     data: Vec<InferenceData>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip)]
+    pub rejection: Option<Rejection>,
+    #[serde(skip)]
+    pub timings: InferenceTimings,
 }
 
 // The model has been trained on this specific
 // sample rate.
-const AUDIO_SAMPLE_RATE: u32 = 16000;
+pub const AUDIO_SAMPLE_RATE: u32 = 16000;
 const AUDIO_CHANNELS: u32 = 1;
 const AUDIO_FORMAT: Format = Format::Wav;
+// Duration of one acoustic model timestep, in seconds.
+const TIMESTEP_DURATION: f32 = 0.02;
 
-fn start_model(model: String, scorer: String) -> Model {
+impl InferenceResult {
+    pub fn is_ok(&self) -> bool {
+        self.status == "ok"
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_ref().map(|e| e.as_str())
+    }
+
+    /// Record `format` applied to every successful transcription.
+    pub fn format_with<F: Fn(&str) -> String>(&mut self, format: F) {
+        if self.is_ok() {
+            for data in self.data.iter_mut() {
+                data.formatted_text = Some(format(&data.text));
+            }
+        }
+    }
+
+    pub fn data(&self) -> &[InferenceData] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut Vec<InferenceData> {
+        &mut self.data
+    }
+
+    /// The most likely transcription, if inference succeeded.
+    pub fn best(&self) -> Option<&InferenceData> {
+        if self.is_ok() {
+            self.data.first()
+        } else {
+            None
+        }
+    }
+}
+
+pub fn start_model(model: String, scorer: String) -> Result<Model, String> {
     let mut m = Model::load_from_files(
         Path::new(&model)
-    ).unwrap();
+    ).map_err(|_| format!("Unable to load model {:?}", model))?;
 
     m.enable_external_scorer(
         Path::new(&scorer)
     );
 
-    m
+    Ok(m)
 }
 
 fn ensure_valid_audio(desc: Description) -> bool {
@@ -98,11 +201,18 @@ fn inference_result(result: String, status: bool) -> InferenceResult {
     inf_data.push(InferenceData {
         confidence: confidence_value,
         text: result,
+        formatted_text: None,
+        words: Vec::new(),
+        redactions: Vec::new(),
+        formatted_redactions: Vec::new(),
     });
 
     let inf_result = InferenceResult {
         status: status_value,
         data: inf_data,
+        error: None,
+        rejection: None,
+        timings: InferenceTimings::default(),
     };
 
     inf_result
@@ -112,11 +222,81 @@ fn inference_error() -> InferenceResult {
     inference_result("".to_string(), false)
 }
 
-fn inference(m: &mut Model, buffer: &[i16]) -> InferenceResult {
+fn inference_rejected(rejection: Rejection, message: String) -> InferenceResult {
+    let mut rv = inference_error();
+    rv.error = Some(message);
+    rv.rejection = Some(rejection);
+    rv
+}
+
+/// Refuse `duration` of audio that its API key has no quota left for.
+pub fn quota_rejected(duration: Duration) -> InferenceResult {
+    let mut rv = inference_rejected(
+        Rejection::QuotaExceeded,
+        "key is over its daily audio quota".to_string(),
+    );
+    rv.timings.audio_duration = Some(duration);
+    rv
+}
+
+fn audio_duration(buffer: &[i16]) -> Duration {
+    let samples = buffer.len() as u64;
+    let rate = u64::from(AUDIO_SAMPLE_RATE);
+    Duration::from_secs(samples / rate)
+        + Duration::from_nanos((samples % rate) * 1_000_000_000 / rate)
+}
+
+/// Group per-character tokens into words, each ending when the token after
+/// its last character starts.
+fn words_from_tokens(tokens: &[(&str, f32)]) -> Vec<WordTiming> {
+    let mut words = Vec::new();
+    let mut current: Option<WordTiming> = None;
+
+    for &(text, start_time) in tokens {
+        if text.trim().is_empty() {
+            if let Some(mut word) = current.take() {
+                word.end = start_time;
+                words.push(word);
+            }
+            continue;
+        }
+
+        match current {
+            Some(ref mut word) => {
+                word.word.push_str(text);
+                word.end = start_time + TIMESTEP_DURATION;
+            }
+            None => {
+                current = Some(WordTiming {
+                    word: text.to_string(),
+                    start: start_time,
+                    end: start_time + TIMESTEP_DURATION,
+                })
+            }
+        }
+    }
+    words.extend(current);
+
+    words
+}
+
+pub fn inference(m: &mut Model, buffer: &[i16]) -> InferenceResult {
     let start = Instant::now();
 
-    let rv = match m.speech_to_text(buffer) {
-        Ok(result) => inference_result(result, true),
+    let mut rv = match m.speech_to_text_with_metadata(buffer, 1) {
+        Ok(ref metadata) if metadata.num_transcripts() > 0 => {
+            let best = &metadata.transcripts()[0];
+            let tokens: Vec<(&str, f32)> = best
+                .tokens()
+                .iter()
+                .map(|t| (t.text().unwrap_or(""), t.start_time()))
+                .collect();
+            let text: String = tokens.iter().map(|&(t, _)| t).collect();
+            let mut rv = inference_result(text, true);
+            rv.data[0].words = words_from_tokens(&tokens);
+            rv
+        }
+        Ok(_) => inference_result("".to_string(), true),
         Err(err) => {
             error!("Error while running inference: {:?}", err);
             inference_error()
@@ -126,117 +306,113 @@ fn inference(m: &mut Model, buffer: &[i16]) -> InferenceResult {
     let duration = start.elapsed();
     info!("Inference took: {:?}", duration);
 
+    rv.timings.audio_duration = Some(audio_duration(buffer));
+    rv.timings.inference_time = Some(duration);
     rv
 }
 
-fn maybe_dump_debug(stream: Bytes, directory: String) {
-    use self::mkstemp::TempFile;
-    use std::io::Write;
-
-    let temp_root = Path::new(&directory);
-    let temp_file_name = temp_root.join("ds-debug-wav-XXXXXX");
-
-    debug!(
-        "Dumping RAW PCM content to {:?} => {:?}",
-        temp_root, temp_file_name
-    );
-
-    match TempFile::new(temp_file_name.to_str().unwrap(), false) {
-        Ok(mut file) => match file.write(&*stream) {
-            Ok(_) => debug!("Sucessfully write debug file"),
-            Err(err) => error!("Error writing content of debug file {:?}", err),
-        },
-        Err(err) => error!("Error creating debug file: {:?}", err),
-    }
-}
-
-fn maybe_warmup_model(mut m: &mut Model, directory: String, cycles: i32) {
-    let warmup_dir = Path::new(&directory);
-    let mut allwaves = Vec::new();
+/// Decode a WAV file, or failing that raw 16-bit PCM, to samples the model
+/// accepts.
+pub fn decode_audio(content: &[u8]) -> Result<Vec<i16>, InferenceResult> {
+    match Reader::new(Cursor::new(content)) {
+        Ok(mut reader) => {
+            let desc = reader.description();
+
+            match ensure_valid_audio(desc) {
+                true => reader.samples().collect::<Result<Vec<_>, _>>().map_err(|err| {
+                    debug!("Audrey sample error: {:?}", err);
+                    inference_rejected(Rejection::InvalidAudio, "truncated WAV".to_string())
+                }),
+                false => Err(inference_rejected(
+                    Rejection::InvalidAudio,
+                    "expected 16kHz mono WAV or raw PCM".to_string(),
+                )),
+            }
+        }
 
-    for entry in warmup_dir.read_dir().expect("read_dir call failed") {
-        if let Ok(entry) = entry {
-            match entry.path().extension() {
-                Some(ext) if ext == "wav" => {
-                    debug!("Found one more WAV file: {:?}", entry.path());
-                    allwaves.push(entry.path());
+        Err(err) => {
+            debug!("Audrey read error: {:?}", err);
+            let mut audio_u8 = content.to_vec();
+            match audio_u8.as_mut_slice_of::<i16>() {
+                Ok(audio_i16) => {
+                    info!("Trying with RAW PCM {:?} bytes", audio_i16.len());
+                    Ok(audio_i16.to_vec())
+                }
+                Err(err) => {
+                    error!("Unable to make u8 -> i16: {:?}", err);
+                    Err(inference_rejected(
+                        Rejection::InvalidAudio,
+                        "raw PCM must be 16-bit samples".to_string(),
+                    ))
                 }
-                Some(_) => {}
-                None => {}
             }
         }
     }
+}
 
-    for wave in allwaves.iter() {
-        debug!("Warmup with {:?}", wave);
-        if let Ok(audio_file) = File::open(wave) {
-            if let Ok(mut reader) = Reader::new(audio_file) {
-                let audio_buf: Vec<_> = reader.samples().map(|s| s.unwrap()).collect::<Vec<_>>();
-                for i in 0..cycles {
-                    info!("Warmup cycle {} of {}", i + 1, cycles);
-                    inference(&mut m, &*audio_buf);
-                }
-            }
+/// Refuse audio longer than `max_audio_duration`, returning its duration.
+pub fn check_audio_duration(
+    buffer: &[i16],
+    max_audio_duration: Option<Duration>,
+) -> Result<Duration, InferenceResult> {
+    let duration = audio_duration(buffer);
+    match max_audio_duration {
+        Some(max) if duration > max => {
+            error!("Audio too long: {:?} > {:?}", duration, max);
+            let mut rv = inference_rejected(
+                Rejection::AudioTooLong,
+                format!(
+                    "audio is {:.1}s long, maximum is {}s",
+                    duration.as_secs() as f64 + f64::from(duration.subsec_millis()) / 1000.0,
+                    max.as_secs()
+                ),
+            );
+            rv.timings.audio_duration = Some(duration);
+            Err(rv)
         }
+        _ => Ok(duration),
     }
 }
 
 pub fn th_inference(
     model: String,
     scorer: String,
-    rx_audio: Receiver<(RawAudioPCM, Sender<InferenceResult>)>,
-    dump_dir: String,
-    warmup_dir: String,
-    warmup_cycles: i32,
+    rx_audio: Receiver<InferenceJob>,
+    warmup: Option<WarmupOptions>,
+    warmup_tracker: Arc<WarmupTracker>,
+    postprocessor: Option<Postprocessor>,
 ) {
     info!("Inference thread started");
-    let mut model_instance = start_model(model, scorer);
+    let mut model_instance = match start_model(model, scorer) {
+        Ok(m) => m,
+        Err(err) => {
+            error!("{}", err);
+            process::exit(1);
+        }
+    };
 
-    if warmup_dir.len() > 0 {
-        maybe_warmup_model(&mut model_instance, warmup_dir.clone(), warmup_cycles);
+    if let Some(ref warmup) = warmup {
+        let worker = thread::current().name().unwrap_or("inference").to_string();
+        warmup::run(&mut model_instance, &worker, warmup, &warmup_tracker);
     }
 
     loop {
         info!("Model ready and waiting for data to infer ...");
         match rx_audio.recv() {
-            Ok((audio, tx_string)) => {
+            Ok(job) => {
+                let _request_id = RequestIdGuard::new(&job.request_id);
+                let audio = job.audio;
+                let queue_wait = job.queued_at.elapsed();
+                debug!("Waited {:?} in queue", queue_wait);
                 info!("Received message: {:?} bytes", audio.content.len());
 
-                #[cfg(feature = "dump_debug_stream")]
-                maybe_dump_debug(audio.content.clone(), dump_dir.clone());
-
-                let inf = match Reader::new(Cursor::new(&*audio.content)) {
-                    Ok(mut reader) => {
-                        let desc = reader.description();
-
-                        match ensure_valid_audio(desc) {
-                            true => {
-                                let audio_buf: Vec<_> =
-                                    reader.samples().map(|s| s.unwrap()).collect::<Vec<_>>();
-                                inference(&mut model_instance, &*audio_buf)
-                            }
-
-                            false => inference_error(),
-                        }
-                    }
-
-                    Err(err) => {
-                        error!("Audrey read error: {:?}", err);
-                        let mut audio_u8 = audio.content.to_vec();
-                        match audio_u8.as_mut_slice_of::<i16>() {
-                            Ok(audio_i16) => {
-                                info!("Trying with RAW PCM {:?} bytes", audio_i16.len());
-                                inference(&mut model_instance, &*audio_i16)
-                            }
-                            Err(err) => {
-                                error!("Unable to make u8 -> i16: {:?}", err);
-                                inference_error()
-                            }
-                        }
-                    }
-                };
-
-                match tx_string.send(inf) {
+                let mut inf = inference(&mut model_instance, &job.samples);
+
+                if let Some(ref postprocessor) = postprocessor {
+                    inf.format_with(|text| postprocessor.format(text));
+                }
+                inf.timings.queue_wait = Some(queue_wait);
+                match job.tx_result.send(inf) {
                     Ok(_) => {}
                     Err(err) => error!("Error sending inference result: {:?}", err),
                 }
@@ -246,3 +422,38 @@ pub fn th_inference(
         }
     }
 }
+
+#[test]
+fn test_words_from_tokens() {
+    let tokens = vec![
+        ("h", 0.1), ("i", 0.2), (" ", 0.4), ("y", 0.6), ("o", 0.7), ("u", 0.8),
+    ];
+    let words = words_from_tokens(&tokens);
+    assert_eq!(words.len(), 2);
+    assert_eq!((words[0].word.as_str(), words[0].start, words[0].end), ("hi", 0.1, 0.4));
+    assert_eq!(words[1].word, "you");
+    assert_eq!(words[1].start, 0.6);
+    assert!((words[1].end - 0.82).abs() < 1e-6);
+}
+
+#[test]
+fn test_decode_truncated_wav() {
+    let mut wav = Vec::new();
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&136u32.to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&AUDIO_SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(AUDIO_SAMPLE_RATE * 2).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&100u32.to_le_bytes());
+    // 100 bytes announced, 11 sent
+    wav.extend_from_slice(&[0; 11]);
+
+    let rejected = decode_audio(&wav).unwrap_err();
+    assert_eq!(rejected.rejection, Some(Rejection::InvalidAudio));
+}
@@ -0,0 +1,102 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Tracks whether the server is accepting work and how many requests are
+/// still being processed, so shutdown can drain them.
+pub struct Lifecycle {
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+    completed: AtomicUsize,
+    rejected: AtomicUsize,
+}
+
+/// Held for as long as a request is queued or running inference.
+pub struct InFlightGuard {
+    lifecycle: Arc<Lifecycle>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.lifecycle.in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.lifecycle.completed.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+pub struct DrainSummary {
+    pub completed: usize,
+    pub rejected: usize,
+    pub abandoned: usize,
+    pub drain_time: Duration,
+}
+
+impl Lifecycle {
+    pub fn new() -> Lifecycle {
+        Lifecycle {
+            draining: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+            completed: AtomicUsize::new(0),
+            rejected: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        !self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Register a new request, unless we are already draining.
+    pub fn track(lifecycle: &Arc<Lifecycle>) -> Option<InFlightGuard> {
+        if !lifecycle.is_ready() {
+            lifecycle.rejected.fetch_add(1, Ordering::SeqCst);
+            return None;
+        }
+
+        lifecycle.in_flight.fetch_add(1, Ordering::SeqCst);
+        Some(InFlightGuard {
+            lifecycle: lifecycle.clone(),
+        })
+    }
+
+    pub fn begin_drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// Wait for in-flight requests to complete, for at most `grace_period`.
+    pub fn drain(&self, grace_period: Duration) -> DrainSummary {
+        let start = Instant::now();
+        while self.in_flight() > 0 && start.elapsed() < grace_period {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        DrainSummary {
+            completed: self.completed.load(Ordering::SeqCst),
+            rejected: self.rejected.load(Ordering::SeqCst),
+            abandoned: self.in_flight(),
+            drain_time: start.elapsed(),
+        }
+    }
+}
+
+#[test]
+fn test_lifecycle_drain() {
+    let lifecycle = Arc::new(Lifecycle::new());
+    let guard = Lifecycle::track(&lifecycle);
+    assert!(guard.is_some());
+    assert_eq!(lifecycle.in_flight(), 1);
+
+    lifecycle.begin_drain();
+    assert!(!lifecycle.is_ready());
+    assert!(Lifecycle::track(&lifecycle).is_none());
+
+    drop(guard);
+    let summary = lifecycle.drain(Duration::from_secs(1));
+    assert_eq!(summary.completed, 1);
+    assert_eq!(summary.rejected, 1);
+    assert_eq!(summary.abandoned, 0);
+}
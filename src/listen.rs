@@ -0,0 +1,161 @@
+extern crate net2;
+
+use self::net2::TcpBuilder;
+
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+
+/// Pending connections the kernel queues for each listener
+const LISTEN_BACKLOG: i32 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Which endpoints a listener serves, others answering `404`
+pub struct RouteSet {
+    /// `POST /` and CORS preflights
+    pub api: bool,
+    /// `/__heartbeat__`, `/__lbheartbeat__` and `/__version__`
+    pub health: bool,
+    /// `/__warmup__`, `/__usage__` and `/__cache__`
+    pub admin: bool,
+}
+
+impl RouteSet {
+    pub const ALL: RouteSet = RouteSet {
+        api: true,
+        health: true,
+        admin: true,
+    };
+
+    /// Parse `+`-separated route set names, such as `api+health`.
+    pub fn parse(names: &str) -> Result<RouteSet, String> {
+        let mut routes = RouteSet {
+            api: false,
+            health: false,
+            admin: false,
+        };
+        for name in names.split('+').map(|name| name.trim()) {
+            match name {
+                "api" => routes.api = true,
+                "health" => routes.health = true,
+                "admin" => routes.admin = true,
+                "all" => routes = RouteSet::ALL,
+                _ => {
+                    return Err(format!(
+                        "unknown routes {:?}, expected api, health, admin or all",
+                        name
+                    ))
+                }
+            }
+        }
+        Ok(routes)
+    }
+
+    /// Whether requests for `path` are served.
+    pub fn allows(&self, path: &str) -> bool {
+        match path {
+            "/__heartbeat__" | "/__lbheartbeat__" | "/__version__" => self.health,
+            "/__warmup__" | "/__usage__" | "/__cache__" => self.admin,
+            _ => self.api,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// An address to serve HTTP on, and what to serve there
+pub struct ListenAddr {
+    pub addr: SocketAddr,
+    pub routes: RouteSet,
+}
+
+/// Parse an `ADDRESS:PORT`, with IPv6 addresses in brackets.
+pub fn parse_addr(addr: &str) -> Result<SocketAddr, String> {
+    let addr = addr
+        .trim()
+        .parse::<SocketAddr>()
+        .map_err(|_| format!("{:?} is not an address and port, such as 0.0.0.0:8080", addr))?;
+    if addr.port() == 0 {
+        return Err(format!("{}: port must be between 1 and 65535", addr));
+    }
+    Ok(addr)
+}
+
+/// Whether listening on `a` would take `b`, or the other way around.
+pub fn overlaps(a: SocketAddr, b: SocketAddr) -> bool {
+    a.port() == b.port() && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+}
+
+/// Parse a comma-separated list of `ADDRESS:PORT[=ROUTES]`, with IPv6
+/// addresses in brackets and every route by default.
+pub fn parse_listen(list: &str) -> Result<Vec<ListenAddr>, String> {
+    let mut listeners: Vec<ListenAddr> = Vec::new();
+    for entry in list.split(',').map(|entry| entry.trim()).filter(|e| !e.is_empty()) {
+        let mut parts = entry.splitn(2, '=');
+        let addr = parse_addr(parts.next().unwrap_or(""))?;
+        if listeners.iter().any(|listener| listener.addr == addr) {
+            return Err(format!("{} is listed twice", addr));
+        }
+        let routes = match parts.next() {
+            Some(names) => RouteSet::parse(names)?,
+            None => RouteSet::ALL,
+        };
+        listeners.push(ListenAddr {
+            addr: addr,
+            routes: routes,
+        });
+    }
+    if listeners.is_empty() {
+        return Err("no address to listen on".to_string());
+    }
+    Ok(listeners)
+}
+
+/// Listen on `addr`, accepting only IPv6 connections on IPv6 addresses when
+/// `ipv6_only`, IPv4-mapped ones too otherwise.
+pub fn bind_tcp(addr: SocketAddr, ipv6_only: bool) -> io::Result<TcpListener> {
+    let builder = match addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => {
+            let builder = TcpBuilder::new_v6()?;
+            builder.only_v6(ipv6_only)?;
+            builder
+        }
+    };
+    builder.reuse_address(true)?;
+    builder.bind(addr)?.listen(LISTEN_BACKLOG)
+}
+
+#[test]
+fn test_parse_listen() {
+    let listeners = parse_listen("0.0.0.0:8080=api+health, [::1]:9090=admin").unwrap();
+    assert_eq!(listeners.len(), 2);
+    assert_eq!(listeners[0].addr, "0.0.0.0:8080".parse().unwrap());
+    assert!(listeners[0].routes.allows("/"));
+    assert!(listeners[0].routes.allows("/__lbheartbeat__"));
+    assert!(!listeners[0].routes.allows("/__usage__"));
+    assert_eq!(listeners[1].addr, "[::1]:9090".parse().unwrap());
+    assert!(!listeners[1].routes.allows("/"));
+    assert!(listeners[1].routes.allows("/__cache__"));
+
+    assert_eq!(parse_listen("127.0.0.1:80").unwrap()[0].routes, RouteSet::ALL);
+    assert!(parse_listen("::1:8080").is_err());
+    assert!(parse_listen("127.0.0.1:0").is_err());
+    assert!(parse_listen("127.0.0.1:80=metrics").is_err());
+    assert!(parse_listen("127.0.0.1:80, 127.0.0.1:80=admin").is_err());
+    assert!(parse_listen(" , ").is_err());
+
+    let any: SocketAddr = "[::]:8080".parse().unwrap();
+    assert!(overlaps(any, "127.0.0.1:8080".parse().unwrap()));
+    assert!(!overlaps(any, "127.0.0.1:9090".parse().unwrap()));
+    assert!(!overlaps("127.0.0.1:8080".parse().unwrap(), "[::1]:8080".parse().unwrap()));
+
+    // Only `[::]` itself, not its IPv4-mapped addresses, on hosts with IPv6
+    let listener = match bind_tcp("[::]:0".parse().unwrap(), true) {
+        Ok(listener) => listener,
+        Err(_) => return,
+    };
+    let port = listener.local_addr().unwrap().port();
+    if ::std::net::TcpStream::connect(("::1", port)).is_err() {
+        return;
+    }
+    assert!(::std::net::TcpStream::connect(("127.0.0.1", port)).is_err());
+}
@@ -0,0 +1,129 @@
+extern crate chrono;
+extern crate log;
+extern crate serde_json;
+
+use self::log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+thread_local! {
+    static REQUEST_ID: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Tags every log line emitted from the current thread with a request ID,
+/// until dropped.
+pub struct RequestIdGuard {
+    previous: Option<String>,
+}
+
+impl RequestIdGuard {
+    pub fn new(request_id: &str) -> RequestIdGuard {
+        let previous = REQUEST_ID.with(|id| id.replace(Some(request_id.to_string())));
+        RequestIdGuard { previous: previous }
+    }
+}
+
+impl Drop for RequestIdGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        REQUEST_ID.with(|id| *id.borrow_mut() = previous);
+    }
+}
+
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.with(|id| id.borrow().clone())
+}
+
+struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+}
+
+impl Logger {
+    fn format_text(&self, record: &Record) -> String {
+        let time = chrono::Local::now().format("%H:%M:%S");
+        match current_request_id() {
+            Some(request_id) => format!(
+                "{} [{}] {}: [{}] {}",
+                time,
+                record.level(),
+                record.target(),
+                request_id,
+                record.args()
+            ),
+            None => format!(
+                "{} [{}] {}: {}",
+                time,
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+        }
+    }
+
+    fn format_json(&self, record: &Record) -> String {
+        json!({
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "level": record.level().to_string(),
+            "target": record.target(),
+            "thread": ::std::thread::current().name(),
+            "request_id": current_request_id(),
+            "message": record.args().to_string(),
+        }).to_string()
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = match self.format {
+            LogFormat::Text => self.format_text(record),
+            LogFormat::Json => self.format_json(record),
+        };
+
+        let stderr = io::stderr();
+        let mut handle = stderr.lock();
+        let _ = writeln!(handle, "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+pub fn init(level: LevelFilter, format: LogFormat) -> Result<(), SetLoggerError> {
+    log::set_boxed_logger(Box::new(Logger {
+        level: level,
+        format: format,
+    }))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+#[test]
+fn test_request_id_guard() {
+    assert_eq!(current_request_id(), None);
+    {
+        let _outer = RequestIdGuard::new("outer");
+        {
+            let _inner = RequestIdGuard::new("inner");
+            assert_eq!(current_request_id(), Some("inner".to_string()));
+        }
+        assert_eq!(current_request_id(), Some("outer".to_string()));
+    }
+    assert_eq!(current_request_id(), None);
+}
@@ -2,33 +2,326 @@
 extern crate log;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
 
-extern crate simplelog;
+extern crate ctrlc;
+extern crate futures;
+// Generated code refers to these from the crate root
+extern crate grpc;
+extern crate protobuf;
+extern crate serde;
 
-use std::sync::mpsc::channel;
+use futures::sync::oneshot;
+use std::fs::File;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::process;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
+
+mod access_log;
+use access_log::AccessLog;
+
+mod alignment;
 
 mod args;
-use args::ArgsParser;
+use args::{ArgsParser, Command};
+
+mod auth;
+use auth::Authenticator;
+
+mod bench;
+
+mod cache;
+use cache::ResultCache;
+
+mod config;
+
+mod cors;
+
+mod evaluate;
+
+mod debug_dump;
+use debug_dump::DebugDumper;
+
+mod grpc_service;
 
 mod http;
-use http::th_http_listener;
+use http::{set_inference_queue, th_http_listener, HttpContext};
 
 mod inference;
-use inference::th_inference;
+use inference::{start_model, th_inference};
+
+mod lifecycle;
+use lifecycle::Lifecycle;
+
+mod listen;
+
+mod logging;
+
+mod offline;
+
+mod proto;
+
+mod postprocess;
+use postprocess::Postprocessor;
+
+mod rate_limit;
+use rate_limit::RateLimiter;
+
+mod redaction;
+use redaction::Redactor;
+
+mod replay;
+
+mod stats;
+
+mod subtitles;
+
+mod tls;
+use tls::build_acceptor;
+
+mod unix_socket;
+
+mod version;
+use version::VersionInfo;
+
+mod warmup;
+use warmup::{WarmupLimit, WarmupOptions, WarmupSource, WarmupTracker};
+
+/// Why the main thread wakes up
+enum Wakeup {
+    /// `SIGTERM` or `SIGINT`
+    Signal,
+    /// The HTTP thread ended, after a shutdown or on its own
+    ListenerStopped,
+}
+
+/// How long to wait, once drained, for the HTTP thread to stop its listeners
+/// and remove their socket file
+const LISTENER_STOP_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often the in-process benchmark checks whether warmup is over
+const WARMUP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Tells the main thread when the HTTP thread ends, even by panicking.
+struct OnStop(Sender<Wakeup>);
+
+impl Drop for OnStop {
+    fn drop(&mut self) {
+        let _ = self.0.send(Wakeup::ListenerStopped);
+    }
+}
+
+/// Run `ds-srv bench` against `target` and return the exit status.
+fn run_bench(target: &str, options: &bench::BenchOptions) -> i32 {
+    match bench::run(target, options) {
+        Ok(report) => {
+            print!("{}", report.summary());
+            if let Some(ref path) = options.report {
+                write_report(path, &report);
+            }
+            0
+        }
+        Err(err) => {
+            eprintln!("Benchmark failed: {}", err);
+            1
+        }
+    }
+}
+
+/// Write a subcommand's JSON report, exiting if that fails.
+fn write_report<T: serde::Serialize>(path: &str, report: &T) {
+    let written = File::create(path).and_then(|mut f| {
+        f.write_all(serde_json::to_string_pretty(report).unwrap().as_bytes())
+    });
+    if let Err(err) = written {
+        eprintln!("Unable to write report to {:?}: {}", path, err);
+        process::exit(1);
+    }
+}
 
 fn main() {
-    let rc = ArgsParser::from_cli();
+    let mut rc = match ArgsParser::from_cli().and_then(|rc| rc.validate().map(|_| rc)) {
+        Ok(rc) => rc,
+        Err(err) => {
+            eprintln!("Invalid configuration: {}", err);
+            process::exit(2);
+        }
+    };
 
     let log_level = rc.verbosity_level.into();
-    let _ = simplelog::TermLogger::init(log_level, simplelog::Config::default());
+    let _ = logging::init(log_level, rc.log_format);
 
     debug!("Parsed all CLI args: {:?}", rc);
 
+    if rc.check_config {
+        match start_model(rc.model.clone(), rc.scorer.clone()) {
+            Ok(_) => {
+                println!("Configuration OK");
+                process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("Invalid configuration: `model`: {}", err);
+                process::exit(2);
+            }
+        }
+    }
+
+    let postprocessor = if rc.postprocess {
+        match Postprocessor::load(rc.replacements_file.as_ref().map(|p| p.as_str())) {
+            Ok(postprocessor) => Some(postprocessor),
+            Err(err) => {
+                eprintln!("Invalid configuration: `replacements_file`: {}", err);
+                process::exit(2);
+            }
+        }
+    } else {
+        None
+    };
+
+    match rc.command {
+        Some(Command::Replay(ref options)) => match replay::run(&rc.model, &rc.scorer, options) {
+            Ok(report) => {
+                print!("{}", report.summary());
+                if let Some(ref path) = options.report {
+                    write_report(path, &report);
+                }
+                process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("Replay failed: {}", err);
+                process::exit(1);
+            }
+        },
+        Some(Command::Transcribe(ref options)) => {
+            match offline::run(&rc.model, &rc.scorer, postprocessor, &rc.subtitles, options) {
+                Ok(0) => process::exit(0),
+                Ok(failed) => {
+                    eprintln!("{} file(s) could not be transcribed", failed);
+                    process::exit(1);
+                }
+                Err(err) => {
+                    eprintln!("Transcription failed: {}", err);
+                    process::exit(1);
+                }
+            }
+        }
+        Some(Command::Evaluate(ref options)) => {
+            match evaluate::run(&rc.model, &rc.scorer, options) {
+                Ok(report) => {
+                    print!("{}", report.summary());
+                    if let Some(ref path) = options.report {
+                        write_report(path, &report);
+                    }
+                    process::exit(if report.has_failed_runs() { 1 } else { 0 });
+                }
+                Err(err) => {
+                    eprintln!("Evaluation failed: {}", err);
+                    process::exit(1);
+                }
+            }
+        }
+        Some(Command::Bench(ref options)) => match options.server {
+            Some(ref server) => process::exit(run_bench(server, options)),
+            // Benchmark a server of our own, once it is up
+            None => rc.http_ip = IpAddr::V4(Ipv4Addr::LOCALHOST),
+        },
+        None => {}
+    }
+
+    let access_log = match rc.access_log {
+        Some(ref path) => match AccessLog::open(
+            path,
+            rc.access_log_format,
+            rc.access_log_max_size,
+            rc.access_log_max_files,
+        ) {
+            Ok(log) => Some(log),
+            Err(err) => {
+                eprintln!("Invalid configuration: `access_log`: {}", err);
+                process::exit(2);
+            }
+        },
+        None => None,
+    };
+
+    let authenticator = match rc.auth_file {
+        Some(ref path) => match Authenticator::load(path) {
+            Ok(authenticator) => Some(authenticator),
+            Err(err) => {
+                eprintln!("Invalid configuration: `auth_file`: {}", err);
+                process::exit(2);
+            }
+        },
+        None => None,
+    };
+
+    let redactor = match Redactor::load(
+        rc.profanity_file.as_ref().map(|p| p.as_str()),
+        rc.redaction_patterns_file.as_ref().map(|p| p.as_str()),
+    ) {
+        Ok(redactor) => redactor,
+        Err(err) => {
+            eprintln!("Invalid configuration: {}", err);
+            process::exit(2);
+        }
+    };
+
+    let tls_acceptor = match rc.tls {
+        Some(ref tls) => match build_acceptor(tls) {
+            Ok(acceptor) => Some(acceptor),
+            Err(err) => {
+                eprintln!("Invalid configuration: `tls_cert`: {}", err);
+                process::exit(2);
+            }
+        },
+        None => None,
+    };
+
     let (tx_audio, rx_audio) = channel();
+    let (tx_signal, rx_signal) = channel();
+    let (tx_shutdown, rx_shutdown) = oneshot::channel();
+    let lifecycle = Arc::new(Lifecycle::new());
+
+    let on_stop = OnStop(tx_signal.clone());
+    ctrlc::set_handler(move || {
+        let _ = tx_signal.send(Wakeup::Signal);
+    }).expect("Unable to install signal handler");
+
+    let warmup_source = match (rc.warmup_dir.as_str(), rc.warmup_synthetic_duration) {
+        ("", 0) => None,
+        ("", seconds) => Some(WarmupSource::Synthetic(Duration::from_secs(seconds))),
+        (dir, _) => Some(WarmupSource::Dir(dir.to_string())),
+    };
+    let warmup = warmup_source.map(|source| WarmupOptions {
+        source: source,
+        limit: match rc.warmup_budget {
+            0 => WarmupLimit::Cycles(rc.warmup_cycles as u32),
+            seconds => WarmupLimit::Budget(Duration::from_secs(seconds)),
+        },
+    });
+    // Not ready until every inference worker is warm
+    let warmup_tracker = Arc::new(WarmupTracker::new());
+    if warmup.is_some() {
+        warmup_tracker.register("InferenceService");
+    }
+
+    set_inference_queue(tx_audio);
+
+    // Cached results depend on the replacements, not on where they are read from
+    let postprocess_identity = match postprocessor {
+        Some(ref postprocessor) => postprocessor.fingerprint(),
+        None => "off".to_string(),
+    };
 
     let mut threads = Vec::new();
     let rc_inference = rc.clone();
+    let warmup_tracker_inference = warmup_tracker.clone();
+    let warmup_tracker_bench = warmup_tracker.clone();
     let thread_inference = thread::Builder::new()
         .name("InferenceService".to_string())
         .spawn(move || {
@@ -36,26 +329,143 @@ fn main() {
                 rc_inference.model,
                 rc_inference.scorer,
                 rx_audio,
-                rc_inference.dump_dir,
-                rc_inference.warmup_dir,
-                rc_inference.warmup_cycles,
+                warmup,
+                warmup_tracker_inference,
+                postprocessor,
             );
         });
     threads.push(thread_inference);
 
+    let (tx_listening, rx_listening) = channel();
     let rc_http = rc.clone();
+    let lifecycle_http = lifecycle.clone();
     let thread_http = thread::Builder::new()
         .name("HttpService".to_string())
         .spawn(move || {
-            th_http_listener(rc_http.http_ip, rc_http.http_port, tx_audio);
+            let _on_stop = on_stop;
+            let version = VersionInfo::collect(&rc_http.model, &rc_http.scorer);
+            let listeners = rc_http.listeners();
+            let ctx = Arc::new(HttpContext {
+                version_json: version.to_json(),
+                version_file: rc_http.version_file,
+                lifecycle: lifecycle_http,
+                warmup: warmup_tracker,
+                access_log: access_log,
+                authenticator: authenticator,
+                rate_limiter: if rc_http.rate_limit > 0.0 {
+                    Some(RateLimiter::new(rc_http.rate_limit, rc_http.rate_limit_burst))
+                } else {
+                    None
+                },
+                max_body_size: rc_http.max_body_size,
+                cors: rc_http.cors,
+                subtitles: rc_http.subtitles,
+                redactor: redactor,
+                max_audio_duration: match rc_http.max_audio_duration {
+                    0 => None,
+                    seconds => Some(Duration::from_secs(seconds)),
+                },
+                cache: if rc_http.cache_size > 0 || rc_http.cache_dir.is_some() {
+                    Some(ResultCache::new(
+                        rc_http.cache_size,
+                        rc_http.cache_dir.as_ref().map(PathBuf::from),
+                        rc_http.cache_dir_max_entries,
+                        &version,
+                        &format!("postprocess={}", postprocess_identity),
+                    ))
+                } else {
+                    None
+                },
+                dumper: DebugDumper::new(
+                    &rc_http.dump_dir,
+                    rc_http.dump_mode,
+                    rc_http.dump_retention,
+                ),
+            });
+            let grpc_limiter = if rc_http.rate_limit > 0.0 {
+                Some(RateLimiter::new(rc_http.rate_limit, rc_http.rate_limit_burst))
+            } else {
+                None
+            };
+            let grpc_max_streams = rc_http.grpc_max_streams;
+            let ipv6_only = rc_http.http_ipv6_only;
+            // Serves until dropped, with the HTTP listener
+            let _grpc_server = rc_http.grpc_listen.map(|addr| {
+                match grpc_service::start(
+                    addr,
+                    ipv6_only,
+                    ctx.clone(),
+                    version,
+                    grpc_limiter,
+                    grpc_max_streams,
+                ) {
+                    Ok(server) => server,
+                    Err(err) => {
+                        error!("{}", err);
+                        process::exit(1);
+                    }
+                }
+            });
+            th_http_listener(
+                listeners,
+                ipv6_only,
+                rc_http.unix_socket,
+                ctx,
+                tls_acceptor,
+                rx_shutdown,
+                tx_listening,
+            );
         });
     threads.push(thread_http);
 
+    for hdl in threads.iter() {
+        if let Err(ref err) = *hdl {
+            error!("Unable to start thread: {:?}", err);
+            process::exit(1);
+        }
+    }
+
     println!("Started all thread.");
 
-    for hdl in threads {
-        if hdl.is_ok() {
-            hdl.unwrap().join().unwrap();
-        }
+    if let Some(Command::Bench(ref options)) = rc.command {
+        let (target, options) = (format!("http://127.0.0.1:{}/", rc.http_port), options.clone());
+        let _ = thread::Builder::new().name("Bench".to_string()).spawn(move || {
+            // Hashing the model and binding come first; if binding fails, the
+            // main thread exits when the HTTP thread stops. Warmup runs
+            // meanwhile and would otherwise compete with the measured run.
+            if rx_listening.recv().is_ok() {
+                while !warmup_tracker_bench.is_done() {
+                    thread::sleep(WARMUP_POLL_INTERVAL);
+                }
+                process::exit(run_bench(&target, &options));
+            }
+        });
     }
+
+    if let Ok(Wakeup::ListenerStopped) = rx_signal.recv() {
+        error!("HTTP listener stopped without a shutdown request");
+        process::exit(1);
+    }
+    info!(
+        "Shutdown requested, draining {} in-flight request(s) for up to {}s",
+        lifecycle.in_flight(),
+        rc.shutdown_grace_period
+    );
+    lifecycle.begin_drain();
+    let _ = tx_shutdown.send(());
+
+    let summary = lifecycle.drain(Duration::from_secs(rc.shutdown_grace_period));
+    if summary.abandoned > 0 {
+        warn!(
+            "Grace period expired with {} request(s) still in flight",
+            summary.abandoned
+        );
+    }
+    info!(
+        "Shutdown complete in {:?}: {} request(s) completed, {} rejected while draining, {} abandoned",
+        summary.drain_time, summary.completed, summary.rejected, summary.abandoned
+    );
+    while let Ok(Wakeup::Signal) = rx_signal.recv_timeout(LISTENER_STOP_TIMEOUT) {}
+
+    process::exit(0);
 }
@@ -0,0 +1,342 @@
+extern crate glob;
+extern crate serde_json;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use http::ResponseFormat;
+use inference::{decode_audio, inference, start_model, InferenceResult};
+use postprocess::Postprocessor;
+use subtitles::{self, SubtitleFormat, SubtitleOptions};
+
+#[derive(Debug, Clone, PartialEq)]
+/// Settings of `ds-srv transcribe`
+pub struct OfflineOptions {
+    /// Files, glob patterns or `-` for standard input
+    pub inputs: Vec<String>,
+    pub format: ResponseFormat,
+    /// How many models transcribe in parallel
+    pub jobs: usize,
+    /// Where to write one output file per input, instead of standard output
+    pub output_dir: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    Stdin,
+    File(PathBuf),
+}
+
+impl Input {
+    pub fn name(&self) -> String {
+        match *self {
+            Input::Stdin => "-".to_string(),
+            Input::File(ref path) => path.to_string_lossy().into_owned(),
+        }
+    }
+
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        let mut content = Vec::new();
+        match *self {
+            Input::Stdin => io::stdin().read_to_end(&mut content)?,
+            Input::File(ref path) => File::open(path)?.read_to_end(&mut content)?,
+        };
+        Ok(content)
+    }
+
+    /// Name of the output file for this input in `--output_dir`.
+    fn output_name(&self, format: ResponseFormat) -> String {
+        let stem = match *self {
+            Input::Stdin => "stdin".to_string(),
+            Input::File(ref path) => path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "audio".to_string()),
+        };
+        let extension = match format {
+            ResponseFormat::Json => "json",
+            ResponseFormat::Text => "txt",
+            ResponseFormat::Subtitles(SubtitleFormat::Srt) => "srt",
+            ResponseFormat::Subtitles(SubtitleFormat::WebVtt) => "vtt",
+        };
+        format!("{}.{}", stem, extension)
+    }
+}
+
+fn is_pattern(input: &str) -> bool {
+    input.contains(&['*', '?', '['][..])
+}
+
+/// Expand glob patterns, which must match something, in the given order.
+pub fn expand(inputs: &[String]) -> Result<Vec<Input>, String> {
+    let mut expanded = Vec::new();
+    for input in inputs {
+        if input == "-" {
+            if expanded.contains(&Input::Stdin) {
+                return Err("`-` can only be given once".to_string());
+            }
+            expanded.push(Input::Stdin);
+        } else if is_pattern(input) {
+            let matches = glob::glob(input)
+                .map_err(|e| format!("invalid pattern {:?}: {}", input, e))?
+                .filter_map(|path| path.ok())
+                .filter(|path| path.is_file())
+                .map(Input::File)
+                .collect::<Vec<_>>();
+            if matches.is_empty() {
+                return Err(format!("no file matches {:?}", input));
+            }
+            expanded.extend(matches);
+        } else {
+            expanded.push(Input::File(PathBuf::from(input)));
+        }
+    }
+    Ok(expanded)
+}
+
+/// Refuse inputs that would be written to the same file in `--output_dir`,
+/// such as `a/take.wav` and `b/take.wav`.
+fn check_output_names(inputs: &[Input], format: ResponseFormat) -> Result<(), String> {
+    let mut names: HashMap<String, &Input> = HashMap::new();
+    for input in inputs {
+        let name = input.output_name(format);
+        if let Some(other) = names.insert(name.clone(), input) {
+            return Err(format!(
+                "{} and {} would both be written to {:?} in --output_dir",
+                other.name(),
+                input.name(),
+                name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// `result` as the server would send it in `format`, or why it cannot be.
+fn render(
+    result: &InferenceResult,
+    format: ResponseFormat,
+    options: &SubtitleOptions,
+) -> Result<String, String> {
+    match (format, result.best()) {
+        (ResponseFormat::Json, _) => Ok(serde_json::to_string(result).unwrap()),
+        (ResponseFormat::Text, Some(best)) => Ok(format!("{}\n", best.display_text())),
+        (ResponseFormat::Subtitles(format), Some(best)) => {
+            Ok(subtitles::render(format, &subtitles::cues(&best.words, options)))
+        }
+        (_, None) => Err(result.error().unwrap_or("inference failed").to_string()),
+    }
+}
+
+/// One line of JSON output, for when several files go to standard output.
+fn json_line(name: &str, result: Result<&InferenceResult, &str>) -> String {
+    let mut value = match result {
+        // Through a string, for f32 timings to keep their short form
+        Ok(result) => serde_json::from_str(&serde_json::to_string(result).unwrap()).unwrap(),
+        Err(err) => json!({"status": "ko", "error": err}),
+    };
+    value["file"] = json!(name);
+    value.to_string()
+}
+
+/// A file read and transcribed by a worker
+struct Transcribed {
+    index: usize,
+    input: Input,
+    result: Result<InferenceResult, String>,
+}
+
+/// Load a model and transcribe inputs from `rx_job` until there are none
+/// left, reporting whether the model loaded on `tx_ready`.
+fn worker(
+    model: String,
+    scorer: String,
+    postprocessor: Arc<Option<Postprocessor>>,
+    rx_job: Arc<Mutex<Receiver<(usize, Input)>>>,
+    tx_ready: Sender<Result<(), String>>,
+    tx_done: Sender<Transcribed>,
+) {
+    let mut model = match start_model(model, scorer) {
+        Ok(model) => {
+            let _ = tx_ready.send(Ok(()));
+            model
+        }
+        Err(err) => {
+            let _ = tx_ready.send(Err(err));
+            return;
+        }
+    };
+
+    loop {
+        let job = rx_job.lock().unwrap().recv();
+        let (index, input) = match job {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        info!("Transcribing {}", input.name());
+        let result = input
+            .read()
+            .map_err(|e| format!("unable to read {}: {}", input.name(), e))
+            .map(|content| {
+                let mut result = match decode_audio(&content) {
+                    Ok(samples) => inference(&mut model, &samples),
+                    Err(rejected) => rejected,
+                };
+                if let Some(ref postprocessor) = *postprocessor {
+                    result.format_with(|text| postprocessor.format(text));
+                }
+                result
+            });
+        let _ = tx_done.send(Transcribed {
+            index: index,
+            input: input,
+            result: result,
+        });
+    }
+}
+
+/// Transcribe `options.inputs` with `options.jobs` models, writing results
+/// in input order, and return how many failed.
+pub fn run(
+    model: &str,
+    scorer: &str,
+    postprocessor: Option<Postprocessor>,
+    subtitle_options: &SubtitleOptions,
+    options: &OfflineOptions,
+) -> Result<usize, String> {
+    let inputs = expand(&options.inputs)?;
+    match options.format {
+        ResponseFormat::Subtitles(_) if options.output_dir.is_none() && inputs.len() > 1 => {
+            return Err("subtitles for several files need --output_dir".to_string())
+        }
+        _ => {}
+    }
+    if options.output_dir.is_some() {
+        check_output_names(&inputs, options.format)?;
+    }
+
+    let (tx_job, rx_job) = channel();
+    let rx_job = Arc::new(Mutex::new(rx_job));
+    let (tx_ready, rx_ready) = channel();
+    let (tx_done, rx_done) = channel();
+    let postprocessor = Arc::new(postprocessor);
+
+    let workers = options.jobs.max(1).min(inputs.len());
+    for i in 0..workers {
+        let (model, scorer) = (model.to_string(), scorer.to_string());
+        let (postprocessor, rx_job) = (postprocessor.clone(), rx_job.clone());
+        let (tx_ready, tx_done) = (tx_ready.clone(), tx_done.clone());
+        thread::Builder::new()
+            .name(format!("TranscribeWorker{}", i))
+            .spawn(move || worker(model, scorer, postprocessor, rx_job, tx_ready, tx_done))
+            .map_err(|e| format!("unable to start worker: {}", e))?;
+    }
+    drop(tx_done);
+    for _ in 0..workers {
+        rx_ready.recv().map_err(|e| e.to_string())??;
+    }
+
+    let total = inputs.len();
+    for job in inputs.into_iter().enumerate() {
+        tx_job.send(job).map_err(|e| e.to_string())?;
+    }
+    drop(tx_job);
+
+    // Workers finish out of order, results are written in order.
+    let mut pending = BTreeMap::new();
+    let mut next = 0;
+    let mut failed = 0;
+    let stdout = io::stdout();
+    for done in rx_done {
+        pending.insert(done.index, done);
+        while let Some(done) = pending.remove(&next) {
+            next += 1;
+            let name = done.input.name();
+            let rendered = done
+                .result
+                .as_ref()
+                .map_err(|e| e.clone())
+                .and_then(|result| render(result, options.format, subtitle_options));
+            if let Err(ref err) = rendered {
+                error!("{}: {}", name, err);
+            }
+            if !done.result.as_ref().map(|r| r.is_ok()).unwrap_or(false) {
+                failed += 1;
+            }
+
+            match options.output_dir {
+                Some(ref dir) => {
+                    if let Ok(ref output) = rendered {
+                        let path = Path::new(dir).join(done.input.output_name(options.format));
+                        File::create(&path)
+                            .and_then(|mut f| f.write_all(output.as_bytes()))
+                            .map_err(|e| format!("unable to write {:?}: {}", path, e))?;
+                        info!("Wrote {:?}", path);
+                    }
+                }
+                None => {
+                    let mut out = stdout.lock();
+                    let written = match (options.format, total) {
+                        (ResponseFormat::Json, 1) => rendered
+                            .as_ref()
+                            .map(|output| writeln!(out, "{}", output))
+                            .unwrap_or(Ok(())),
+                        (ResponseFormat::Json, _) => writeln!(
+                            out,
+                            "{}",
+                            json_line(&name, done.result.as_ref().map_err(|e| e.as_str()))
+                        ),
+                        (ResponseFormat::Text, n) if n > 1 => match rendered {
+                            Ok(ref text) => write!(out, "{}: {}", name, text),
+                            Err(_) => Ok(()),
+                        },
+                        _ => match rendered {
+                            Ok(ref output) => write!(out, "{}", output),
+                            Err(_) => Ok(()),
+                        },
+                    };
+                    written.map_err(|e| format!("unable to write output: {}", e))?;
+                }
+            }
+        }
+    }
+
+    Ok(failed)
+}
+
+#[test]
+fn test_expand() {
+    let dir = ::std::env::temp_dir().join(format!("ds-srv-expand-{}", ::std::process::id()));
+    let _ = ::std::fs::remove_dir_all(&dir);
+    ::std::fs::create_dir_all(&dir).unwrap();
+    for name in &["b.wav", "a.wav", "notes.txt"] {
+        File::create(dir.join(name)).unwrap();
+    }
+
+    let pattern = dir.join("*.wav").to_string_lossy().into_owned();
+    let inputs = expand(&["-".to_string(), pattern.clone()]).unwrap();
+    assert_eq!(
+        inputs,
+        vec![
+            Input::Stdin,
+            Input::File(dir.join("a.wav")),
+            Input::File(dir.join("b.wav")),
+        ]
+    );
+    assert_eq!(inputs[1].output_name(ResponseFormat::Text), "a.txt");
+    assert!(check_output_names(&inputs, ResponseFormat::Text).is_ok());
+    let twice = vec![
+        Input::File(dir.join("a.wav")),
+        Input::File(dir.join("other").join("a.raw")),
+    ];
+    assert!(check_output_names(&twice, ResponseFormat::Json).unwrap_err().contains("a.json"));
+    assert!(expand(&["-".to_string(), "-".to_string()]).is_err());
+    assert!(expand(&[dir.join("*.mp3").to_string_lossy().into_owned()]).is_err());
+
+    let _ = ::std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,488 @@
+extern crate sha2;
+extern crate toml;
+
+use self::sha2::{Digest, Sha256};
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NumberKind {
+    Unit,
+    Teen,
+    Tens,
+    Hundred,
+    Scale,
+}
+
+const UNITS: &[(&str, &str)] = &[
+    ("zero", "zeroth"),
+    ("one", "first"),
+    ("two", "second"),
+    ("three", "third"),
+    ("four", "fourth"),
+    ("five", "fifth"),
+    ("six", "sixth"),
+    ("seven", "seventh"),
+    ("eight", "eighth"),
+    ("nine", "ninth"),
+];
+
+const TEENS: &[(&str, &str)] = &[
+    ("ten", "tenth"),
+    ("eleven", "eleventh"),
+    ("twelve", "twelfth"),
+    ("thirteen", "thirteenth"),
+    ("fourteen", "fourteenth"),
+    ("fifteen", "fifteenth"),
+    ("sixteen", "sixteenth"),
+    ("seventeen", "seventeenth"),
+    ("eighteen", "eighteenth"),
+    ("nineteen", "nineteenth"),
+];
+
+const TENS: &[(&str, &str)] = &[
+    ("twenty", "twentieth"),
+    ("thirty", "thirtieth"),
+    ("forty", "fortieth"),
+    ("fifty", "fiftieth"),
+    ("sixty", "sixtieth"),
+    ("seventy", "seventieth"),
+    ("eighty", "eightieth"),
+    ("ninety", "ninetieth"),
+];
+
+const SCALES: &[(&str, &str, u64)] = &[
+    ("thousand", "thousandth", 1_000),
+    ("million", "millionth", 1_000_000),
+    ("billion", "billionth", 1_000_000_000),
+];
+
+const MONTHS: &[&str] = &[
+    "january", "february", "march", "april", "may", "june", "july", "august", "september",
+    "october", "november", "december",
+];
+
+/// Months that are also common verbs, only read as dates with an ordinal day
+/// ("may fifth") or a year ("march three two thousand")
+const AMBIGUOUS_MONTHS: &[&str] = &["march", "may"];
+
+const WEEKDAYS: &[&str] = &[
+    "monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday",
+];
+
+/// Currency names, with the symbol written before amounts of them
+const CURRENCIES: &[(&str, &str, &str)] = &[
+    ("dollar", "dollars", "$"),
+    ("euro", "euros", "€"),
+    ("pound", "pounds", "£"),
+];
+
+fn number_word(word: &str) -> Option<(u64, NumberKind, bool)> {
+    let lookup = |table: &[(&str, &str)], kind, offset| {
+        table.iter().enumerate().find_map(|(i, &(cardinal, ordinal))| {
+            if word == cardinal {
+                Some((i as u64 + offset, kind, false))
+            } else if word == ordinal {
+                Some((i as u64 + offset, kind, true))
+            } else {
+                None
+            }
+        })
+    };
+
+    lookup(UNITS, NumberKind::Unit, 0)
+        .or_else(|| lookup(TEENS, NumberKind::Teen, 10))
+        .or_else(|| {
+            lookup(TENS, NumberKind::Tens, 2).map(|(v, kind, ordinal)| (v * 10, kind, ordinal))
+        })
+        .or_else(|| match word {
+            "hundred" => Some((100, NumberKind::Hundred, false)),
+            "hundredth" => Some((100, NumberKind::Hundred, true)),
+            _ => SCALES.iter().find_map(|&(cardinal, ordinal, value)| {
+                if word == cardinal {
+                    Some((value, NumberKind::Scale, false))
+                } else if word == ordinal {
+                    Some((value, NumberKind::Scale, true))
+                } else {
+                    None
+                }
+            }),
+        })
+}
+
+fn may_follow(previous: Option<NumberKind>, next: NumberKind) -> bool {
+    use self::NumberKind::*;
+    match (previous, next) {
+        (None, Hundred) | (None, Scale) => false,
+        (None, _) => true,
+        (Some(Unit), Hundred) | (Some(Unit), Scale) => true,
+        (Some(Teen), Hundred) | (Some(Teen), Scale) => true,
+        (Some(Tens), Unit) | (Some(Tens), Scale) => true,
+        (Some(Hundred), Hundred) => false,
+        (Some(Hundred), _) => true,
+        (Some(Scale), Unit) | (Some(Scale), Teen) | (Some(Scale), Tens) => true,
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SpokenNumber {
+    value: u64,
+    ordinal: bool,
+    /// How many words the number was spelled with
+    words: usize,
+}
+
+/// Read the longest spelled-out number at the start of `words`.
+fn parse_number(words: &[&str]) -> Option<SpokenNumber> {
+    let mut total = 0;
+    let mut current = 0;
+    let mut last_scale = u64::max_value();
+    let mut previous: Option<NumberKind> = None;
+    let mut parsed: Option<SpokenNumber> = None;
+    let mut i = 0;
+
+    while i < words.len() {
+        let mut next = i;
+        if words[i] == "and" {
+            match previous {
+                Some(NumberKind::Hundred) | Some(NumberKind::Scale) => next += 1,
+                _ => break,
+            }
+        }
+
+        let (value, kind, ordinal) = match words.get(next).and_then(|w| number_word(w)) {
+            Some(word) => word,
+            None => break,
+        };
+        if !may_follow(previous, kind)
+            || (next > i && kind != NumberKind::Unit && kind != NumberKind::Teen
+                && kind != NumberKind::Tens)
+            || (previous.is_some() && value == 0)
+        {
+            break;
+        }
+
+        match kind {
+            NumberKind::Hundred => current *= 100,
+            NumberKind::Scale => {
+                if value >= last_scale {
+                    break;
+                }
+                total += current * value;
+                current = 0;
+                last_scale = value;
+            }
+            _ => current += value,
+        }
+
+        previous = Some(kind);
+        i = next + 1;
+        parsed = Some(SpokenNumber {
+            value: total + current,
+            ordinal: ordinal,
+            words: i,
+        });
+
+        if ordinal || (kind == NumberKind::Unit && value == 0) {
+            break;
+        }
+    }
+
+    parsed
+}
+
+fn ordinal_suffix(value: u64) -> &'static str {
+    match (value % 10, value % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    }
+}
+
+/// Digits, grouped by thousands from 10000 up so that years read naturally.
+fn format_number(value: u64) -> String {
+    let digits = value.to_string();
+    if value < 10_000 {
+        return digits;
+    }
+
+    let mut grouped = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    grouped
+}
+
+/// Years spoken as a number ("two thousand five") or as pairs of digits
+/// ("nineteen ninety nine", "nineteen oh five").
+fn parse_year(words: &[&str]) -> Option<(u64, usize)> {
+    let first = parse_number(words)?;
+    if first.ordinal {
+        return None;
+    }
+    if first.value >= 1000 && first.value < 3000 {
+        return Some((first.value, first.words));
+    }
+    if first.value < 10 || first.value > 99 {
+        return None;
+    }
+
+    let rest = &words[first.words..];
+    if rest.first() == Some(&"oh") {
+        return match rest.get(1).and_then(|w| number_word(w)) {
+            Some((value, NumberKind::Unit, false)) if value > 0 => {
+                Some((first.value * 100 + value, first.words + 2))
+            }
+            _ => None,
+        };
+    }
+    match parse_number(rest) {
+        Some(second) if !second.ordinal && second.value >= 10 && second.value <= 99 => {
+            Some((first.value * 100 + second.value, first.words + second.words))
+        }
+        _ => None,
+    }
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Month name followed by a day, and possibly a year.
+fn date_at(words: &[&str]) -> Option<(String, usize)> {
+    if !MONTHS.contains(&words[0]) {
+        return None;
+    }
+    let day = parse_number(&words[1..])?;
+    if day.value < 1 || day.value > 31 {
+        return None;
+    }
+
+    let mut date = format!("{} {}", capitalize(words[0]), day.value);
+    let mut consumed = 1 + day.words;
+    match parse_year(&words[consumed..]) {
+        Some((year, year_words)) => {
+            date.push_str(&format!(", {}", year));
+            consumed += year_words;
+        }
+        None if AMBIGUOUS_MONTHS.contains(&words[0]) && !day.ordinal => return None,
+        None => {}
+    }
+    Some((date, consumed))
+}
+
+/// A number, possibly an amount of money, possibly with cents.
+fn number_at(words: &[&str]) -> Option<(String, usize)> {
+    let number = parse_number(words)?;
+    let mut consumed = number.words;
+
+    if !number.ordinal {
+        let currency = words.get(consumed).and_then(|w| {
+            CURRENCIES
+                .iter()
+                .find(|&&(singular, plural, _)| w == &singular || w == &plural)
+        });
+        if let Some(&(_, _, symbol)) = currency {
+            consumed += 1;
+            let mut amount = format!("{}{}", symbol, format_number(number.value));
+            if words.get(consumed) == Some(&"and") {
+                let cents = parse_number(&words[consumed + 1..]);
+                let unit = cents.and_then(|c| words.get(consumed + 1 + c.words));
+                match (cents, unit) {
+                    (Some(cents), Some(&unit))
+                        if !cents.ordinal
+                            && cents.value < 100
+                            && (unit == "cent" || unit == "cents") =>
+                    {
+                        amount.push_str(&format!(".{:02}", cents.value));
+                        consumed += 1 + cents.words + 1;
+                    }
+                    _ => {}
+                }
+            }
+            return Some((amount, consumed));
+        }
+    }
+
+    // Small numbers on their own read better spelled out, ordinals included.
+    if number.words == 1 && number.value < 10 {
+        return None;
+    }
+
+    let mut text = format_number(number.value);
+    if number.ordinal {
+        text.push_str(ordinal_suffix(number.value));
+    }
+    Some((text, consumed))
+}
+
+/// Rule-based inverse text normalization: spelled-out numbers, ordinals,
+/// amounts of money and dates written the way people write them.
+fn normalize(words: &[&str]) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < words.len() {
+        match date_at(&words[i..]).or_else(|| number_at(&words[i..])) {
+            Some((text, consumed)) => {
+                out.push(text);
+                i += consumed;
+            }
+            None => {
+                out.push(words[i].to_string());
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+fn is_pronoun_i(word: &str) -> bool {
+    word == "i" || word.starts_with("i'")
+}
+
+/// Turns raw DeepSpeech output into something readable.
+pub struct Postprocessor {
+    /// Phrases to replace, as lowercase words, longest first
+    replacements: Vec<(Vec<String>, String)>,
+}
+
+impl Postprocessor {
+    pub fn new(replacements: BTreeMap<String, String>) -> Postprocessor {
+        let mut replacements: Vec<(Vec<String>, String)> = replacements
+            .into_iter()
+            .map(|(from, to)| {
+                let words = from.split_whitespace().map(|w| w.to_lowercase()).collect();
+                (words, to)
+            })
+            .filter(|r: &(Vec<String>, String)| !r.0.is_empty())
+            .collect();
+        replacements.sort_by_key(|r| Reverse(r.0.len()));
+
+        Postprocessor {
+            replacements: replacements,
+        }
+    }
+
+    /// Load a TOML file mapping phrases to what they should be replaced with.
+    pub fn load(path: Option<&str>) -> Result<Postprocessor, String> {
+        let replacements = match path {
+            Some(path) => {
+                let mut content = String::new();
+                File::open(path)
+                    .and_then(|mut f| f.read_to_string(&mut content))
+                    .map_err(|e| format!("unable to read {:?}: {}", path, e))?;
+                toml::from_str(&content)
+                    .map_err(|e| format!("unable to parse {:?}: {}", path, e))?
+            }
+            None => BTreeMap::new(),
+        };
+        Ok(Postprocessor::new(replacements))
+    }
+
+    /// A digest of the replacements, changing whenever the output may.
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        for &(ref from, ref to) in self.replacements.iter() {
+            hasher.input(from.join(" ").as_bytes());
+            hasher.input([0]);
+            hasher.input(to.as_bytes());
+            hasher.input([0]);
+        }
+        format!("{:x}", hasher.result())
+    }
+
+    fn replace(&self, words: Vec<String>) -> Vec<String> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < words.len() {
+            let matched = self.replacements.iter().find(|&&(ref from, _)| {
+                i + from.len() <= words.len()
+                    && from
+                        .iter()
+                        .zip(words[i..].iter())
+                        .all(|(f, w)| *f == w.to_lowercase())
+            });
+            match matched {
+                Some(&(ref from, ref to)) => {
+                    out.push(to.clone());
+                    i += from.len();
+                }
+                None => {
+                    out.push(words[i].clone());
+                    i += 1;
+                }
+            }
+        }
+        out
+    }
+
+    pub fn format(&self, raw: &str) -> String {
+        let lowercase = raw.to_lowercase();
+        let words: Vec<&str> = lowercase.split_whitespace().collect();
+
+        let mut words: Vec<String> = normalize(&words)
+            .into_iter()
+            .map(|w| {
+                if is_pronoun_i(&w) || WEEKDAYS.contains(&w.as_str()) {
+                    capitalize(&w)
+                } else {
+                    w
+                }
+            })
+            .collect();
+        if let Some(first) = words.first_mut() {
+            *first = capitalize(first);
+        }
+
+        let mut text = self.replace(words).join(" ");
+        if text.chars().last().map(|c| c.is_alphanumeric()).unwrap_or(false) {
+            text.push('.');
+        }
+        text
+    }
+}
+
+#[test]
+fn test_postprocess() {
+    let mut replacements = BTreeMap::new();
+    replacements.insert("deep speech".to_string(), "DeepSpeech".to_string());
+    let pp = Postprocessor::new(replacements.clone());
+    assert_eq!(pp.fingerprint(), Postprocessor::new(replacements.clone()).fingerprint());
+    assert!(pp.fingerprint() != Postprocessor::new(BTreeMap::new()).fingerprint());
+
+    assert_eq!(pp.format(""), "");
+    assert_eq!(pp.format("it costs twenty three dollars"), "It costs $23.");
+    assert_eq!(
+        pp.format("i paid one hundred and five euros and fifty cents"),
+        "I paid €105.50."
+    );
+    assert_eq!(
+        pp.format("call me on march third nineteen ninety nine"),
+        "Call me on March 3, 1999."
+    );
+    assert_eq!(pp.format("see you july fourth two thousand and five"), "See you July 4, 2005.");
+    assert_eq!(pp.format("the twenty first floor"), "The 21st floor.");
+    assert_eq!(pp.format("one two three"), "One two three.");
+    assert_eq!(pp.format("twenty five thousand people"), "25,000 people.");
+    assert_eq!(pp.format("we ship deep speech on monday"), "We ship DeepSpeech on Monday.");
+
+    assert_eq!(pp.format("we may one day"), "We may one day.");
+    assert_eq!(pp.format("they march four miles"), "They march four miles.");
+    assert_eq!(pp.format("wait a second"), "Wait a second.");
+    assert_eq!(pp.format("on may fifth"), "On May 5.");
+    assert_eq!(pp.format("since march two twenty twenty"), "Since March 2, 2020.");
+    assert_eq!(pp.format("june two"), "June 2.");
+}
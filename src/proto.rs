@@ -0,0 +1,4 @@
+//! Messages of `proto/ds_srv.proto`, generated by `build.rs`
+#![allow(bare_trait_objects, renamed_and_removed_lints, unused_imports, clippy::all)]
+
+include!(concat!(env!("OUT_DIR"), "/ds_srv.rs"));
@@ -0,0 +1,112 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Track at most this many clients, forgetting the one seen first when a new
+/// one comes.
+const MAX_TRACKED_CLIENTS: usize = 10000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Buckets<K> {
+    by_client: HashMap<K, Bucket>,
+    /// Clients in the order they were first seen, oldest first
+    order: VecDeque<K>,
+}
+
+/// Per-client token buckets refilled at `rate` requests per second, holding
+/// at most `burst` tokens. Clients are IP addresses, or API key ids for gRPC.
+pub struct RateLimiter<K = IpAddr> {
+    rate: f64,
+    burst: f64,
+    capacity: usize,
+    buckets: Mutex<Buckets<K>>,
+}
+
+impl<K: Hash + Eq + Clone> RateLimiter<K> {
+    pub fn new(rate: f64, burst: u32) -> RateLimiter<K> {
+        RateLimiter {
+            rate: rate,
+            burst: f64::from(burst.max(1)),
+            capacity: MAX_TRACKED_CLIENTS,
+            buckets: Mutex::new(Buckets {
+                by_client: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.duration_since(bucket.updated);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+    }
+
+    /// Take a token for `client`, or tell how long until one is available.
+    pub fn check(&self, client: K) -> Result<(), Duration> {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: K, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let buckets = &mut *buckets;
+
+        if !buckets.by_client.contains_key(&client) {
+            if buckets.order.len() >= self.capacity {
+                if let Some(oldest) = buckets.order.pop_front() {
+                    buckets.by_client.remove(&oldest);
+                }
+            }
+            buckets.order.push_back(client.clone());
+        }
+
+        let burst = self.burst;
+        let bucket = buckets.by_client.entry(client).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        self.refill(bucket, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / self.rate;
+            Err(Duration::from_millis((wait * 1000.0).ceil() as u64))
+        }
+    }
+}
+
+#[test]
+fn test_rate_limiter() {
+    let limiter: RateLimiter = RateLimiter::new(2.0, 3);
+    let client: IpAddr = "192.0.2.1".parse().unwrap();
+    let other: IpAddr = "192.0.2.2".parse().unwrap();
+    let start = Instant::now();
+
+    for _ in 0..3 {
+        assert!(limiter.check_at(client, start).is_ok());
+    }
+    assert_eq!(limiter.check_at(client, start), Err(Duration::from_millis(500)));
+    assert!(limiter.check_at(other, start).is_ok());
+
+    assert!(limiter.check_at(client, start + Duration::from_millis(500)).is_ok());
+    assert!(limiter.check_at(client, start + Duration::from_millis(500)).is_err());
+
+    // A full limiter forgets the client it saw first
+    let mut limiter: RateLimiter = RateLimiter::new(1.0, 1);
+    limiter.capacity = 2;
+    let third: IpAddr = "192.0.2.3".parse().unwrap();
+    assert!(limiter.check_at(client, start).is_ok());
+    assert!(limiter.check_at(other, start).is_ok());
+    assert!(limiter.check_at(third, start).is_ok());
+    assert_eq!(limiter.buckets.lock().unwrap().by_client.len(), 2);
+    assert!(limiter.check_at(other, start).is_err());
+    assert!(limiter.check_at(client, start).is_ok());
+}
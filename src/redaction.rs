@@ -0,0 +1,395 @@
+extern crate regex;
+extern crate toml;
+
+use self::regex::{Regex, RegexBuilder};
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+
+use config::ConfigError;
+use inference::{InferenceResult, WordTiming};
+
+/// Word stems masked by default, `*` matching any ending
+const DEFAULT_PROFANITY: &[&str] = &[
+    "asshole*", "bastard*", "bitch*", "bullshit*", "cunt*", "dick", "dickhead*", "fuck*",
+    "motherfuck*", "shit*",
+];
+
+/// Spoken or written digits, as DeepSpeech and post-processing produce them
+const DIGIT: &str = r"(?:zero|oh|one|two|three|four|five|six|seven|eight|nine|\d)";
+
+/// Top-level domains a spoken email address may end with, leaving out those
+/// that are common words, so that "look at this dot and that" is not one
+const SPOKEN_TLD: &str = "(?:com|org|net|edu|gov|info|biz|io|co|uk|de|fr|ca|au|nl|ch|jp)";
+
+fn default_patterns() -> Vec<(String, String)> {
+    vec![
+        (
+            "card_number".to_string(),
+            format!(r"\b(?:{}[\s-]*){{13,19}}\b", DIGIT),
+        ),
+        (
+            "phone_number".to_string(),
+            format!(r"\+?\b(?:{}[\s-]*){{7,12}}\b", DIGIT),
+        ),
+        (
+            "email".to_string(),
+            format!(
+                concat!(
+                    r"\b[\w.+-]+(?:@[\w-]+(?:\.[\w-]+)*\.[a-z]{{2,}}",
+                    r"| at [\w-]+(?: dot [\w-]+)* dot {})\b"
+                ),
+                SPOKEN_TLD
+            ),
+        ),
+    ]
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+/// What a client asked to hide from its transcript
+pub struct RedactionOptions {
+    pub mask_profanity: bool,
+    pub redact_pii: bool,
+}
+
+impl RedactionOptions {
+    pub fn any(&self) -> bool {
+        self.mask_profanity || self.redact_pii
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// A masked part of a transcript, as character offsets into it
+pub struct Redaction {
+    pub kind: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Words `first` to `last` (inclusive) to be shown as `text`
+struct Replacement {
+    first: usize,
+    last: usize,
+    kind: String,
+    text: String,
+}
+
+/// Split `word` into leading punctuation, the word itself and trailing
+/// punctuation.
+fn split_punctuation(word: &str) -> (&str, &str, &str) {
+    let start = word
+        .find(|c: char| c.is_alphanumeric())
+        .unwrap_or(word.len());
+    let end = word
+        .rfind(|c: char| c.is_alphanumeric())
+        .map(|i| i + word[i..].chars().next().unwrap().len_utf8())
+        .unwrap_or(start);
+    (&word[..start], &word[start..end], &word[end..])
+}
+
+fn mask(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => ::std::iter::once(first).chain(chars.map(|_| '*')).collect(),
+        None => String::new(),
+    }
+}
+
+pub struct Redactor {
+    /// Lowercase words, or stems when ending with `*`
+    profanity: Vec<String>,
+    /// Named patterns, tried in order (by name when read from a file)
+    patterns: Vec<(String, Regex)>,
+}
+
+impl Redactor {
+    pub fn new(
+        profanity: Vec<String>,
+        patterns: Vec<(String, String)>,
+    ) -> Result<Redactor, String> {
+        let patterns = patterns
+            .into_iter()
+            .map(|(name, pattern)| {
+                RegexBuilder::new(&pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .map(|re| (name.clone(), re))
+                    .map_err(|e| format!("invalid pattern {:?}: {}", name, e))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Redactor {
+            profanity: profanity.into_iter().map(|w| w.to_lowercase()).collect(),
+            patterns: patterns,
+        })
+    }
+
+    /// Load the word list (one word per line, `#` starting comments) and the
+    /// TOML table of named patterns, falling back to built-in ones.
+    pub fn load(
+        profanity_file: Option<&str>,
+        patterns_file: Option<&str>,
+    ) -> Result<Redactor, ConfigError> {
+        let read = |path: &str| {
+            let mut content = String::new();
+            File::open(path)
+                .and_then(|mut f| f.read_to_string(&mut content))
+                .map(|_| content)
+                .map_err(|e| format!("unable to read {:?}: {}", path, e))
+        };
+
+        let profanity = match profanity_file {
+            Some(path) => read(path)
+                .map_err(|e| ConfigError::new("profanity_file", e))?
+                .lines()
+                .map(|line| line.split('#').next().unwrap_or("").trim().to_string())
+                .filter(|word| !word.is_empty())
+                .collect(),
+            None => DEFAULT_PROFANITY.iter().map(|w| w.to_string()).collect(),
+        };
+
+        let patterns = match patterns_file {
+            Some(path) => {
+                let patterns: BTreeMap<String, String> = read(path)
+                    .and_then(|content| {
+                        toml::from_str(&content)
+                            .map_err(|e| format!("unable to parse {:?}: {}", path, e))
+                    })
+                    .map_err(|e| ConfigError::new("redaction_patterns_file", e))?;
+                patterns.into_iter().collect()
+            }
+            None => default_patterns(),
+        };
+
+        Redactor::new(profanity, patterns)
+            .map_err(|e| ConfigError::new("redaction_patterns_file", e))
+    }
+
+    fn is_profane(&self, word: &str) -> bool {
+        let word = word.to_lowercase();
+        self.profanity.iter().any(|entry| {
+            if entry.ends_with('*') {
+                word.starts_with(&entry[..entry.len() - 1])
+            } else {
+                word == *entry
+            }
+        })
+    }
+
+    fn replacements(&self, text: &str, options: &RedactionOptions) -> Vec<Replacement> {
+        let mut offset = 0;
+        let words: Vec<(usize, &str)> = text
+            .split(' ')
+            .map(|word| {
+                let start = offset;
+                offset += word.len() + 1;
+                (start, word)
+            })
+            .filter(|&(_, word)| !word.is_empty())
+            .collect();
+        let mut redacted = vec![false; words.len()];
+        let mut replacements = Vec::new();
+
+        if options.redact_pii {
+            for &(ref name, ref pattern) in self.patterns.iter() {
+                for found in pattern.find_iter(text) {
+                    let covered: Vec<usize> = (0..words.len())
+                        .filter(|&i| {
+                            let (start, word) = words[i];
+                            start < found.end() && start + word.len() > found.start()
+                        })
+                        .collect();
+                    if covered.is_empty() || covered.iter().any(|&i| redacted[i]) {
+                        continue;
+                    }
+
+                    let (first, last) = (covered[0], covered[covered.len() - 1]);
+                    let (prefix, _, _) = split_punctuation(words[first].1);
+                    let (_, _, suffix) = split_punctuation(words[last].1);
+                    for &i in covered.iter() {
+                        redacted[i] = true;
+                    }
+                    replacements.push(Replacement {
+                        first: first,
+                        last: last,
+                        kind: name.clone(),
+                        text: format!("{}[{}]{}", prefix, name.to_uppercase(), suffix),
+                    });
+                }
+            }
+        }
+
+        if options.mask_profanity {
+            for (i, &(_, word)) in words.iter().enumerate() {
+                let (prefix, core, suffix) = split_punctuation(word);
+                if !redacted[i] && !core.is_empty() && self.is_profane(core) {
+                    replacements.push(Replacement {
+                        first: i,
+                        last: i,
+                        kind: "profanity".to_string(),
+                        text: format!("{}{}{}", prefix, mask(core), suffix),
+                    });
+                }
+            }
+        }
+
+        replacements.sort_by_key(|r| r.first);
+        replacements
+    }
+
+    /// Rebuild `words` with replacements, returning each replacement's
+    /// position in the result.
+    fn rebuild<T, F>(
+        words: Vec<T>,
+        replacements: &[Replacement],
+        make: F,
+    ) -> Vec<(T, Option<usize>)>
+    where
+        T: Clone,
+        F: Fn(&[T], &Replacement) -> T,
+    {
+        let mut out = Vec::new();
+        let mut pending = replacements.iter().enumerate().peekable();
+        let mut i = 0;
+        while i < words.len() {
+            match pending.peek() {
+                Some(&(index, replacement)) if replacement.first == i => {
+                    out.push((make(&words[i..=replacement.last], replacement), Some(index)));
+                    i = replacement.last + 1;
+                    pending.next();
+                }
+                _ => {
+                    out.push((words[i].clone(), None));
+                    i += 1;
+                }
+            }
+        }
+        out
+    }
+
+    /// Mask `text`, returning it and where its masked parts now are.
+    pub fn redact_text(&self, text: &str, options: &RedactionOptions) -> (String, Vec<Redaction>) {
+        let replacements = self.replacements(text, options);
+        let words: Vec<String> = text
+            .split(' ')
+            .filter(|w| !w.is_empty())
+            .map(String::from)
+            .collect();
+
+        let mut out = String::new();
+        let mut redactions = Vec::new();
+        for (word, replaced) in Redactor::rebuild(words, &replacements, |_, r| r.text.clone()) {
+            if !out.is_empty() {
+                out.push(' ');
+            }
+            let start = out.chars().count();
+            out.push_str(&word);
+            if let Some(index) = replaced {
+                redactions.push(Redaction {
+                    kind: replacements[index].kind.clone(),
+                    start: start,
+                    end: out.chars().count(),
+                });
+            }
+        }
+        (out, redactions)
+    }
+
+    fn redact_words(
+        &self,
+        text: &str,
+        words: Vec<WordTiming>,
+        options: &RedactionOptions,
+    ) -> Vec<WordTiming> {
+        let replacements = self.replacements(text, options);
+        Redactor::rebuild(words, &replacements, |covered, r| WordTiming {
+            word: r.text.clone(),
+            start: covered[0].start,
+            end: covered[covered.len() - 1].end,
+        }).into_iter()
+            .map(|(word, _)| word)
+            .collect()
+    }
+
+    /// Mask every transcription of `result`, and their word timings.
+    pub fn apply(&self, result: &mut InferenceResult, options: &RedactionOptions) {
+        if !options.any() {
+            return;
+        }
+
+        for data in result.data_mut().iter_mut() {
+            if data.words.len() == data.text.split_whitespace().count() {
+                let words = ::std::mem::replace(&mut data.words, Vec::new());
+                data.words = self.redact_words(&data.text, words, options);
+            } else {
+                data.words.clear();
+            }
+
+            let (text, redactions) = self.redact_text(&data.text, options);
+            data.text = text;
+            data.redactions = redactions;
+
+            if let Some(formatted) = data.formatted_text.take() {
+                let (text, redactions) = self.redact_text(&formatted, options);
+                data.formatted_text = Some(text);
+                data.formatted_redactions = redactions;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_redact() {
+    let redactor = Redactor::load(None, None).unwrap();
+    let all = RedactionOptions {
+        mask_profanity: true,
+        redact_pii: true,
+    };
+
+    let (text, redactions) = redactor.redact_text(
+        "well shit my card is four one one one one one one one one one one one one one one one",
+        &all,
+    );
+    assert_eq!(text, "well s*** my card is [CARD_NUMBER]");
+    assert_eq!(
+        redactions,
+        vec![
+            Redaction { kind: "profanity".to_string(), start: 5, end: 9 },
+            Redaction { kind: "card_number".to_string(), start: 21, end: 34 },
+        ]
+    );
+
+    let (text, _) = redactor.redact_text("Call 555 1234 or mail john at example dot com.", &all);
+    assert_eq!(text, "Call [PHONE_NUMBER] or mail [EMAIL].");
+    let (text, _) = redactor.redact_text("write to jane.doe@mail.example.it", &all);
+    assert_eq!(text, "write to [EMAIL]");
+    let (text, _) = redactor.redact_text("jane at mail dot example dot co dot uk", &all);
+    assert_eq!(text, "[EMAIL]");
+    // Speech that merely says "at" and "dot" is left alone
+    for speech in &["look at this dot and that", "look at this dot in the corner"] {
+        assert_eq!(redactor.redact_text(speech, &all).0, *speech);
+    }
+
+    let profanity_only = RedactionOptions {
+        mask_profanity: true,
+        redact_pii: false,
+    };
+    let (text, _) = redactor.redact_text("fucking 555 1234", &profanity_only);
+    assert_eq!(text, "f****** 555 1234");
+
+    let words = vec![
+        WordTiming { word: "call".to_string(), start: 0.0, end: 0.5 },
+        WordTiming { word: "five".to_string(), start: 1.0, end: 1.2 },
+        WordTiming { word: "five".to_string(), start: 1.3, end: 1.5 },
+        WordTiming { word: "five".to_string(), start: 1.6, end: 1.8 },
+        WordTiming { word: "one".to_string(), start: 2.0, end: 2.2 },
+        WordTiming { word: "two".to_string(), start: 2.3, end: 2.5 },
+        WordTiming { word: "three".to_string(), start: 2.6, end: 2.8 },
+        WordTiming { word: "four".to_string(), start: 2.9, end: 3.1 },
+    ];
+    let words = redactor.redact_words("call five five five one two three four", words, &all);
+    assert_eq!(words.len(), 2);
+    assert_eq!(words[1].word, "[PHONE_NUMBER]");
+    assert_eq!((words[1].start, words[1].end), (1.0, 3.1));
+}
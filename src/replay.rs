@@ -0,0 +1,289 @@
+extern crate deepspeech;
+extern crate futures;
+extern crate hyper;
+extern crate serde_json;
+extern crate tokio;
+
+use self::deepspeech::Model;
+use self::futures::{Future, Stream};
+use self::hyper::client::HttpConnector;
+use self::hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use self::hyper::{Body, Client, Method, Request, Uri};
+use self::tokio::runtime::Runtime;
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use alignment::{align, render_diff};
+use inference::{decode_audio, inference, start_model, InferenceResult};
+
+#[derive(Debug, Clone, PartialEq)]
+/// Settings of `ds-srv replay`
+pub struct ReplayOptions {
+    pub dump_dir: String,
+    /// Base URL of a running server to send audio to, instead of a local model
+    pub server: Option<String>,
+    pub api_key: Option<String>,
+    /// Where to write the JSON report
+    pub report: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+/// The parts of a dump's JSON sidecar we replay
+struct Sidecar {
+    request_id: String,
+    audio_file: Option<String>,
+    result: Option<InferenceResult>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplayEntry {
+    pub request_id: String,
+    pub audio_file: String,
+    /// Transcript when the audio was dumped, if it succeeded then
+    pub recorded: Option<String>,
+    pub replayed: Option<String>,
+    /// Why the audio could not be transcribed again
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub changed: bool,
+    /// Words substituted, deleted or inserted since the recording
+    pub word_edits: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplayReport {
+    pub dump_dir: String,
+    /// Model and scorer, or server, the audio was replayed with
+    pub replayed_with: String,
+    pub total: usize,
+    pub changed: usize,
+    pub unchanged: usize,
+    pub failed: usize,
+    pub recorded_words: usize,
+    pub word_edits: usize,
+    pub entries: Vec<ReplayEntry>,
+}
+
+/// A running server to send audio to
+struct Remote {
+    runtime: Runtime,
+    client: Client<HttpConnector>,
+    uri: Uri,
+    api_key: Option<String>,
+}
+
+/// Where dumped audio is transcribed again
+enum Transcriber {
+    Local(Model),
+    Remote(Box<Remote>),
+}
+
+impl Transcriber {
+    fn remote(server: &str, api_key: Option<String>) -> Result<Transcriber, String> {
+        let uri = server
+            .parse::<Uri>()
+            .map_err(|e| format!("invalid server URL {:?}: {}", server, e))?;
+        if uri.scheme_str() != Some("http") {
+            return Err(format!("server URL {:?} must start with http://", server));
+        }
+        Ok(Transcriber::Remote(Box::new(Remote {
+            runtime: Runtime::new().map_err(|e| format!("unable to start runtime: {}", e))?,
+            client: Client::new(),
+            uri: uri,
+            api_key: api_key,
+        })))
+    }
+
+    fn transcribe(&mut self, audio: &[u8]) -> Result<InferenceResult, String> {
+        match *self {
+            Transcriber::Local(ref mut model) => match decode_audio(audio) {
+                Ok(samples) => Ok(inference(model, &samples)),
+                Err(rejected) => Ok(rejected),
+            },
+            Transcriber::Remote(ref mut remote) => {
+                let mut request = Request::builder();
+                request
+                    .method(Method::POST)
+                    .uri(remote.uri.clone())
+                    .header(CONTENT_TYPE, "application/octet-stream")
+                    .header(CACHE_CONTROL, "no-cache");
+                if let Some(ref key) = remote.api_key {
+                    request.header("X-Api-Key", key.as_str());
+                }
+                let request = request
+                    .body(Body::from(audio.to_vec()))
+                    .map_err(|e| e.to_string())?;
+
+                let client = &remote.client;
+                let (status, body) = remote
+                    .runtime
+                    .block_on(client.request(request).and_then(|response| {
+                        let status = response.status();
+                        response.into_body().concat2().map(move |body| (status, body))
+                    }))
+                    .map_err(|e| format!("request failed: {}", e))?;
+                serde_json::from_slice::<InferenceResult>(&body).map_err(|_| {
+                    format!("server answered {}: {}", status, String::from_utf8_lossy(&body))
+                })
+            }
+        }
+    }
+}
+
+fn read_sidecar(path: &Path) -> Result<Sidecar, String> {
+    File::open(path)
+        .map_err(|e| e.to_string())
+        .and_then(|file| serde_json::from_reader(file).map_err(|e| e.to_string()))
+        .map_err(|e| format!("unable to read {:?}: {}", path, e))
+}
+
+fn replay_one(transcriber: &mut Transcriber, dir: &Path, sidecar: Sidecar) -> ReplayEntry {
+    let recorded = sidecar
+        .result
+        .as_ref()
+        .and_then(|r| r.best())
+        .map(|best| best.text.clone());
+    let audio_file = sidecar.audio_file.unwrap_or_default();
+
+    let mut audio = Vec::new();
+    let replayed = File::open(dir.join(&audio_file))
+        .and_then(|mut f| f.read_to_end(&mut audio))
+        .map_err(|e| format!("unable to read {:?}: {}", audio_file, e))
+        .and_then(|_| transcriber.transcribe(&audio))
+        .and_then(|result| match result.best() {
+            Some(best) => Ok(best.text.clone()),
+            None => Err(result.error().unwrap_or("inference failed").to_string()),
+        });
+
+    let mut entry = ReplayEntry {
+        request_id: sidecar.request_id,
+        audio_file: audio_file,
+        recorded: recorded,
+        replayed: None,
+        error: None,
+        changed: false,
+        word_edits: 0,
+        diff: None,
+    };
+    match replayed {
+        Ok(text) => {
+            if let Some(ref recorded) = entry.recorded {
+                let before: Vec<&str> = recorded.split_whitespace().collect();
+                let after: Vec<&str> = text.split_whitespace().collect();
+                let edits = align(&before, &after);
+                entry.word_edits = edits.iter().filter(|e| e.is_error()).count();
+                entry.changed = entry.word_edits > 0;
+                if entry.changed {
+                    entry.diff = Some(render_diff(&edits));
+                }
+            } else {
+                // Failed when recorded, transcribed now
+                entry.changed = true;
+            }
+            entry.replayed = Some(text);
+        }
+        Err(err) => {
+            entry.changed = entry.recorded.is_some();
+            entry.error = Some(err);
+        }
+    }
+    entry
+}
+
+/// Transcribe every dump in `options.dump_dir` again, with the model and
+/// scorer or against `options.server`, and compare with what was recorded.
+pub fn run(model: &str, scorer: &str, options: &ReplayOptions) -> Result<ReplayReport, String> {
+    let dir = Path::new(&options.dump_dir);
+    let mut sidecars: Vec<_> = fs::read_dir(dir)
+        .map_err(|e| format!("unable to read {:?}: {}", dir, e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            name.starts_with("ds-debug-") && name.ends_with(".json")
+        })
+        .collect();
+    // Dumps are named after the time they were taken
+    sidecars.sort();
+
+    let (mut transcriber, replayed_with) = match options.server {
+        Some(ref server) => (
+            Transcriber::remote(server, options.api_key.clone())?,
+            server.clone(),
+        ),
+        None => (
+            Transcriber::Local(start_model(model.to_string(), scorer.to_string())?),
+            format!("model={} scorer={}", model, scorer),
+        ),
+    };
+
+    let mut report = ReplayReport {
+        dump_dir: options.dump_dir.clone(),
+        replayed_with: replayed_with,
+        total: 0,
+        changed: 0,
+        unchanged: 0,
+        failed: 0,
+        recorded_words: 0,
+        word_edits: 0,
+        entries: Vec::new(),
+    };
+    for path in sidecars {
+        let sidecar = match read_sidecar(&path) {
+            Ok(sidecar) => sidecar,
+            Err(err) => {
+                error!("Skipping dump: {}", err);
+                continue;
+            }
+        };
+        info!("Replaying {:?}", path);
+        let entry = replay_one(&mut transcriber, dir, sidecar);
+
+        report.total += 1;
+        if entry.error.is_some() {
+            report.failed += 1;
+        }
+        if entry.changed {
+            report.changed += 1;
+        } else {
+            report.unchanged += 1;
+        }
+        if let (Some(recorded), None) = (entry.recorded.as_ref(), entry.error.as_ref()) {
+            report.recorded_words += recorded.split_whitespace().count();
+            report.word_edits += entry.word_edits;
+        }
+        report.entries.push(entry);
+    }
+    Ok(report)
+}
+
+impl ReplayReport {
+    /// Changed transcripts and totals, for reading in a terminal.
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+        for entry in self.entries.iter().filter(|e| e.changed || e.error.is_some()) {
+            out.push_str(&format!("{} ({})\n", entry.request_id, entry.audio_file));
+            match (entry.diff.as_ref(), entry.error.as_ref(), entry.replayed.as_ref()) {
+                (Some(diff), _, _) => out.push_str(&format!("  {}\n", diff)),
+                (_, Some(error), _) => out.push_str(&format!("  error: {}\n", error)),
+                (_, _, Some(replayed)) => out.push_str(&format!("  now: {}\n", replayed)),
+                _ => {}
+            }
+        }
+        out.push_str(&format!(
+            "{} replayed with {}: {} changed, {} unchanged, {} failed, \
+             {} word edits over {} recorded words\n",
+            self.total,
+            self.replayed_with,
+            self.changed,
+            self.unchanged,
+            self.failed,
+            self.word_edits,
+            self.recorded_words
+        ));
+        out
+    }
+}
@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+use access_log::millis;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+/// Distribution of durations, in milliseconds
+pub struct LatencySummary {
+    pub count: usize,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+/// Nearest-rank percentile `p` (0 to 100) of `sorted`.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.max(1).min(sorted.len()) - 1]
+}
+
+impl LatencySummary {
+    pub fn new(durations: &[Duration]) -> LatencySummary {
+        let mut sorted: Vec<f64> = durations.iter().map(|d| millis(*d)).collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        if sorted.is_empty() {
+            return LatencySummary::default();
+        }
+
+        LatencySummary {
+            count: sorted.len(),
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            p50: percentile(&sorted, 50.0),
+            p90: percentile(&sorted, 90.0),
+            p95: percentile(&sorted, 95.0),
+            p99: percentile(&sorted, 99.0),
+            max: sorted[sorted.len() - 1],
+        }
+    }
+}
+
+#[test]
+fn test_latency_summary() {
+    let durations: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+    let summary = LatencySummary::new(&durations);
+    assert_eq!(summary.count, 100);
+    assert_eq!(summary.mean, 50.5);
+    assert_eq!((summary.p50, summary.p90, summary.p99, summary.max), (50.0, 90.0, 99.0, 100.0));
+    assert_eq!(LatencySummary::new(&[]), LatencySummary::default());
+}
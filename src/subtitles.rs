@@ -0,0 +1,159 @@
+use inference::WordTiming;
+
+/// Subtitle cues never hold more than this many lines
+const MAX_LINES_PER_CUE: usize = 2;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum SubtitleFormat {
+    Srt,
+    WebVtt,
+}
+
+#[derive(Debug, Clone, Copy)]
+/// How transcripts are split into cues
+pub struct SubtitleOptions {
+    /// Longest line, in characters, unless a single word is longer
+    pub max_line_length: usize,
+    /// Longest time a cue stays on screen, in seconds
+    pub max_cue_duration: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cue {
+    pub start: f32,
+    pub end: f32,
+    pub lines: Vec<String>,
+}
+
+impl SubtitleFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            SubtitleFormat::Srt => "application/x-subrip",
+            SubtitleFormat::WebVtt => "text/vtt; charset=utf-8",
+        }
+    }
+}
+
+/// Greedily fill lines of at most `max_length` characters with `words`.
+fn wrap(words: &[&str], max_length: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for word in words {
+        let fits = match lines.last() {
+            Some(line) => line.chars().count() + 1 + word.chars().count() <= max_length,
+            None => false,
+        };
+        if fits {
+            let line = lines.last_mut().unwrap();
+            line.push(' ');
+            line.push_str(word);
+        } else {
+            lines.push(word.to_string());
+        }
+    }
+    lines
+}
+
+/// Split timed words into cues no longer than the configured duration and
+/// line count.
+pub fn cues(words: &[WordTiming], options: &SubtitleOptions) -> Vec<Cue> {
+    let mut cues = Vec::new();
+    let mut current: Vec<&WordTiming> = Vec::new();
+
+    let make_cue = |words: &[&WordTiming]| Cue {
+        start: words[0].start,
+        end: words[words.len() - 1].end,
+        lines: wrap(
+            &words.iter().map(|w| w.word.as_str()).collect::<Vec<_>>(),
+            options.max_line_length,
+        ),
+    };
+
+    for word in words {
+        if !current.is_empty() {
+            let too_long = word.end - current[0].start > options.max_cue_duration;
+            let mut candidate = current.clone();
+            candidate.push(word);
+            if too_long || make_cue(&candidate).lines.len() > MAX_LINES_PER_CUE {
+                cues.push(make_cue(&current));
+                current.clear();
+            }
+        }
+        current.push(word);
+    }
+    if !current.is_empty() {
+        cues.push(make_cue(&current));
+    }
+
+    cues
+}
+
+/// `HH:MM:SS<separator>mmm`, as both SRT and WebVTT expect.
+fn timestamp(seconds: f32, separator: char) -> String {
+    let millis = (f64::from(seconds.max(0.0)) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        separator,
+        millis % 1000
+    )
+}
+
+pub fn render(format: SubtitleFormat, cues: &[Cue]) -> String {
+    let mut out = String::new();
+    if format == SubtitleFormat::WebVtt {
+        out.push_str("WEBVTT\n\n");
+    }
+
+    let separator = match format {
+        SubtitleFormat::Srt => ',',
+        SubtitleFormat::WebVtt => '.',
+    };
+    for (index, cue) in cues.iter().enumerate() {
+        if format == SubtitleFormat::Srt {
+            out.push_str(&format!("{}\n", index + 1));
+        }
+        out.push_str(&format!(
+            "{} --> {}\n",
+            timestamp(cue.start, separator),
+            timestamp(cue.end, separator)
+        ));
+        for line in cue.lines.iter() {
+            out.push_str(line);
+            out.push('\n');
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[test]
+fn test_subtitles() {
+    let words: Vec<WordTiming> = "the quick brown fox jumps over the lazy dog"
+        .split(' ')
+        .enumerate()
+        .map(|(i, w)| WordTiming {
+            word: w.to_string(),
+            start: i as f32,
+            end: i as f32 + 0.5,
+        })
+        .collect();
+    let options = SubtitleOptions {
+        max_line_length: 10,
+        max_cue_duration: 4.0,
+    };
+
+    let cues = cues(&words, &options);
+    assert_eq!(cues.len(), 3);
+    assert_eq!(cues[0].lines, vec!["the quick", "brown fox"]);
+    assert_eq!(cues[1].lines, vec!["jumps over", "the lazy"]);
+    assert_eq!(cues[2].lines, vec!["dog"]);
+    assert_eq!((cues[2].start, cues[2].end), (8.0, 8.5));
+
+    let srt = render(SubtitleFormat::Srt, &cues[..1]);
+    assert_eq!(srt, "1\n00:00:00,000 --> 00:00:03,500\nthe quick\nbrown fox\n\n");
+    let vtt = render(SubtitleFormat::WebVtt, &cues[2..]);
+    assert_eq!(vtt, "WEBVTT\n\n00:00:08.000 --> 00:00:08.500\ndog\n\n");
+    assert_eq!(timestamp(3723.25, ','), "01:02:03,250");
+}
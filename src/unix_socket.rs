@@ -0,0 +1,118 @@
+extern crate tokio;
+
+use self::tokio::net::UnixListener;
+
+use std::fs::{self, DirBuilder, Permissions};
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq)]
+/// Where to serve HTTP on a Unix domain socket
+pub struct UnixSocketOptions {
+    pub path: String,
+    /// Permissions of the socket file, as with `chmod`
+    pub mode: u32,
+}
+
+/// Parse octal file permissions, as `chmod` takes them.
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        Ok(_) => Err("permissions must be at most 777".to_string()),
+        Err(_) => Err("expected octal file permissions, such as 660".to_string()),
+    }
+}
+
+/// Remove a socket file left behind by a server that is gone, refusing to
+/// replace anything else or a socket still in use.
+fn remove_stale(path: &Path) -> Result<(), String> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(format!("unable to check {:?}: {}", path, err)),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(format!("{:?} exists and is not a socket", path));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(format!("{:?} is in use by another server", path));
+    }
+    info!("Removing stale socket {:?}", path);
+    fs::remove_file(path).map_err(|e| format!("unable to remove {:?}: {}", path, e))
+}
+
+#[derive(Debug)]
+/// The socket file we bound, removed when dropped
+pub struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.0) {
+            warn!("Unable to remove socket {:?}: {}", self.0, err);
+        }
+    }
+}
+
+/// Listen on `options.path`, with `options.mode` permissions. The socket is
+/// bound in a directory only we can enter and moved into place once it has
+/// its permissions, so that nobody connects before.
+pub fn bind(options: &UnixSocketOptions) -> Result<(UnixListener, SocketFile), String> {
+    let path = Path::new(&options.path);
+    let name = path
+        .file_name()
+        .ok_or_else(|| format!("{:?} is not a file path", path))?;
+    remove_stale(path)?;
+
+    let private = path.with_file_name(format!(".ds-srv-{}", ::std::process::id()));
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .map_err(|e| format!("unable to create {:?}: {}", private, e))?;
+    let staged = private.join(name);
+    let bound = UnixListener::bind(&staged)
+        .map_err(|e| format!("unable to bind {:?}: {}", path, e))
+        .and_then(|listener| {
+            fs::set_permissions(&staged, Permissions::from_mode(options.mode))
+                .map_err(|e| format!("unable to set permissions of {:?}: {}", path, e))?;
+            fs::rename(&staged, path).map_err(|e| format!("unable to move {:?}: {}", path, e))?;
+            Ok(listener)
+        });
+    let _ = fs::remove_dir_all(&private);
+    Ok((bound?, SocketFile(path.to_path_buf())))
+}
+
+#[test]
+fn test_unix_socket() {
+    assert_eq!(parse_mode("660"), Ok(0o660));
+    assert_eq!(parse_mode("0600"), Ok(0o600));
+    assert!(parse_mode("1777").is_err());
+    assert!(parse_mode("rw-").is_err());
+
+    let dir = ::std::env::temp_dir().join(format!("ds-srv-socket-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let options = UnixSocketOptions {
+        path: dir.join("ds-srv.sock").to_string_lossy().into_owned(),
+        mode: 0o600,
+    };
+
+    // A socket nobody listens on any more is replaced
+    drop(::std::os::unix::net::UnixListener::bind(&options.path).unwrap());
+    let bound = bind(&options).unwrap();
+    let mode = fs::metadata(&options.path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    // The private directory it was bound in is gone
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    // A server failing to bind leaves the socket in use alone
+    assert!(bind(&options).unwrap_err().contains("in use"));
+    assert!(Path::new(&options.path).exists());
+    drop(bound);
+    assert!(!Path::new(&options.path).exists());
+
+    fs::write(&options.path, "not a socket").unwrap();
+    assert!(bind(&options).unwrap_err().contains("not a socket"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
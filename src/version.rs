@@ -0,0 +1,104 @@
+extern crate deepspeech;
+extern crate serde_json;
+extern crate sha2;
+
+use self::sha2::{Digest, Sha256};
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelIdentity {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeepSpeechVersion {
+    pub crate_version: String,
+    pub native_version: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+/// Build metadata served on `/__version__`
+pub struct VersionInfo {
+    pub version: String,
+    pub commit: String,
+    pub build_timestamp: u64,
+    pub source: String,
+    pub deepspeech: DeepSpeechVersion,
+    pub model: Option<ModelIdentity>,
+    pub scorer: Option<ModelIdentity>,
+}
+
+fn hash_file(path: &Path) -> io::Result<ModelIdentity> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1 << 20];
+    let mut size = 0u64;
+
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.input(&buf[..len]);
+        size += len as u64;
+    }
+
+    Ok(ModelIdentity {
+        path: path.to_string_lossy().into_owned(),
+        size: size,
+        sha256: format!("{:x}", hasher.result()),
+    })
+}
+
+fn identify(path: &str) -> Option<ModelIdentity> {
+    match hash_file(Path::new(path)) {
+        Ok(identity) => Some(identity),
+        Err(err) => {
+            error!("Unable to identify {:?}: {:?}", path, err);
+            None
+        }
+    }
+}
+
+impl VersionInfo {
+    /// Collect build-time metadata and identify the loaded model and scorer.
+    pub fn collect(model: &str, scorer: &str) -> VersionInfo {
+        let native_version = match deepspeech::deepspeech_version() {
+            Ok(v) => v,
+            Err(err) => {
+                error!("Unable to query DeepSpeech version: {:?}", err);
+                "unknown".to_string()
+            }
+        };
+
+        VersionInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            commit: env!("DS_SRV_GIT_COMMIT").to_string(),
+            build_timestamp: env!("DS_SRV_BUILD_TIMESTAMP").parse().unwrap_or(0),
+            source: "https://github.com/mozilla-services/deepspeech-server".to_string(),
+            deepspeech: DeepSpeechVersion {
+                crate_version: env!("DS_SRV_DEEPSPEECH_CRATE_VERSION").to_string(),
+                native_version: native_version,
+            },
+            model: identify(model),
+            scorer: identify(scorer),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Read an on-disk `version.json`, overriding the embedded metadata.
+pub fn read_version_file(path: &str) -> io::Result<String> {
+    let mut json_version = String::new();
+    let mut file = File::open(path)?;
+    file.read_to_string(&mut json_version)?;
+    Ok(json_version)
+}
@@ -0,0 +1,364 @@
+extern crate deepspeech;
+
+use self::deepspeech::Model;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use access_log::millis;
+use inference::{decode_audio, inference, AUDIO_SAMPLE_RATE};
+use stats::LatencySummary;
+
+/// Extensions of the audio `decode_audio` accepts, compared case-insensitively
+const AUDIO_EXTENSIONS: &[&str] = &["wav", "wave", "raw", "pcm"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// How long to keep warming up
+pub enum WarmupLimit {
+    /// Transcribe every file that many times
+    Cycles(u32),
+    /// Transcribe the files over and over until that much time went by
+    Budget(Duration),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// What to warm up on
+pub enum WarmupSource {
+    /// Audio files in that directory
+    Dir(String),
+    /// Generated silence, noise and tone sweeps, that long in total
+    Synthetic(Duration),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WarmupOptions {
+    pub source: WarmupSource,
+    pub limit: WarmupLimit,
+}
+
+/// A decoded warmup file, or generated audio
+pub struct WarmupAudio {
+    pub name: String,
+    pub samples: Vec<i16>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+/// How warming up a worker's model went, so far
+pub struct WarmupStatus {
+    pub worker: String,
+    pub done: bool,
+    /// Warmup directory, or `synthetic`
+    pub source: String,
+    pub files: usize,
+    /// Files found but not decodable
+    pub skipped: usize,
+    pub inferences: usize,
+    pub elapsed_ms: f64,
+    /// Mean latency of each pass over the files, which should settle once
+    /// the model is warm
+    pub pass_means_ms: Vec<f64>,
+    pub latency_ms: LatencySummary,
+}
+
+/// Warmup progress of every inference worker, for readiness checks.
+pub struct WarmupTracker {
+    workers: Mutex<BTreeMap<String, WarmupStatus>>,
+}
+
+impl WarmupTracker {
+    pub fn new() -> WarmupTracker {
+        WarmupTracker {
+            workers: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record that `worker` has to warm up before we are ready.
+    pub fn register(&self, worker: &str) {
+        self.update(WarmupStatus {
+            worker: worker.to_string(),
+            ..WarmupStatus::default()
+        });
+    }
+
+    pub fn update(&self, status: WarmupStatus) {
+        self.workers.lock().unwrap().insert(status.worker.clone(), status);
+    }
+
+    /// Whether every registered worker is done warming up.
+    pub fn is_done(&self) -> bool {
+        self.workers.lock().unwrap().values().all(|status| status.done)
+    }
+
+    pub fn statuses(&self) -> Vec<WarmupStatus> {
+        self.workers.lock().unwrap().values().cloned().collect()
+    }
+}
+
+fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| AUDIO_EXTENSIONS.iter().any(|known| ext.eq_ignore_ascii_case(known)))
+        .unwrap_or(false)
+}
+
+/// Audio files anywhere under `dir`, in path order. Symbolic links to
+/// directories are not followed, so that a link cycle cannot loop forever.
+pub fn discover(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut found = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = fs::read_dir(&dir).map_err(|e| format!("unable to read {:?}: {}", dir, e))?;
+        for entry in entries {
+            let (path, file_type) = match entry.and_then(|e| e.file_type().map(|t| (e.path(), t))) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    warn!("Unable to read an entry of {:?}: {}", dir, err);
+                    continue;
+                }
+            };
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if is_audio(&path) {
+                debug!("Found warmup file {:?}", path);
+                found.push(path);
+            }
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Read and decode the audio under `dir`, returning it with how many files
+/// had to be skipped.
+pub fn load(dir: &Path) -> Result<(Vec<WarmupAudio>, usize), String> {
+    let mut audio = Vec::new();
+    let mut skipped = 0;
+    for path in discover(dir)? {
+        let mut content = Vec::new();
+        let decoded = File::open(&path)
+            .and_then(|mut f| f.read_to_end(&mut content))
+            .map_err(|e| e.to_string())
+            .and_then(|_| {
+                decode_audio(&content)
+                    .map_err(|rejected| rejected.error().unwrap_or("invalid audio").to_string())
+            });
+        match decoded {
+            Ok(ref samples) if samples.is_empty() => {
+                warn!("Skipping warmup file {:?}: no audio", path);
+                skipped += 1;
+            }
+            Ok(samples) => audio.push(WarmupAudio {
+                name: path.to_string_lossy().into_owned(),
+                samples: samples,
+            }),
+            Err(err) => {
+                warn!("Skipping warmup file {:?}: {}", path, err);
+                skipped += 1;
+            }
+        }
+    }
+    Ok((audio, skipped))
+}
+
+/// Tiny xorshift generator, for noise that is the same on every start
+struct Noise(u32);
+
+impl Noise {
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        f64::from(self.0) / f64::from(u32::max_value()) * 2.0 - 1.0
+    }
+}
+
+/// `seconds` of silence, white noise or a 100Hz to 4kHz sweep.
+fn synthesize(kind: &str, seconds: f64) -> Vec<i16> {
+    let rate = f64::from(AUDIO_SAMPLE_RATE);
+    let count = (seconds * rate) as usize;
+    let mut noise = Noise(0x2545_f491);
+    let (low, high) = (100.0, 4000.0);
+    (0..count)
+        .map(|i| {
+            let t = i as f64 / rate;
+            let amplitude = match kind {
+                "noise" => 0.1 * noise.next(),
+                "sweep" => {
+                    // Linear chirp: the phase integrates a rising frequency
+                    let phase = low * t + (high - low) * t * t / (2.0 * seconds);
+                    0.3 * (2.0 * ::std::f64::consts::PI * phase).sin()
+                }
+                _ => 0.0,
+            };
+            (amplitude * f64::from(i16::max_value())) as i16
+        })
+        .collect()
+}
+
+/// Silence, noise and sweeps of doubling lengths, from half a second, adding
+/// up to at most `total`.
+pub fn synthetic(total: Duration) -> Vec<WarmupAudio> {
+    let total = total.as_secs_f64();
+    let mut audio = Vec::new();
+    let (mut used, mut length) = (0.0, 0.5);
+    while used + length <= total {
+        for kind in &["silence", "noise", "sweep"] {
+            if used + length > total {
+                break;
+            }
+            audio.push(WarmupAudio {
+                name: format!("{} {}s", kind, length),
+                samples: synthesize(kind, length),
+            });
+            used += length;
+        }
+        length *= 2.0;
+    }
+    audio
+}
+
+/// Warm `model` up on the audio `options` asks for, reporting progress to
+/// `tracker` as `worker` after every pass over it.
+pub fn run(model: &mut Model, worker: &str, options: &WarmupOptions, tracker: &WarmupTracker) {
+    let mut status = WarmupStatus {
+        worker: worker.to_string(),
+        ..WarmupStatus::default()
+    };
+    let audio = match options.source {
+        WarmupSource::Dir(ref dir) => {
+            status.source = dir.clone();
+            match load(Path::new(dir)) {
+                Ok((audio, skipped)) => {
+                    status.skipped = skipped;
+                    audio
+                }
+                Err(err) => {
+                    error!("Unable to warm up: {}", err);
+                    Vec::new()
+                }
+            }
+        }
+        WarmupSource::Synthetic(duration) => {
+            status.source = "synthetic".to_string();
+            synthetic(duration)
+        }
+    };
+    status.files = audio.len();
+    if audio.is_empty() {
+        warn!("No usable warmup audio in {}", status.source);
+    }
+
+    let started = Instant::now();
+    let mut latencies = Vec::new();
+    let mut pass = 0;
+    let cycles_left = |pass: u32| match options.limit {
+        WarmupLimit::Cycles(cycles) => pass < cycles,
+        WarmupLimit::Budget(_) => true,
+    };
+    // At least one inference, however small the budget
+    let out_of_time = |inferences: usize| match options.limit {
+        WarmupLimit::Cycles(_) => false,
+        WarmupLimit::Budget(budget) => inferences > 0 && started.elapsed() >= budget,
+    };
+    while !audio.is_empty() && cycles_left(pass) && !out_of_time(latencies.len()) {
+        pass += 1;
+        let mut pass_latencies = Vec::new();
+        for file in audio.iter() {
+            if out_of_time(latencies.len()) {
+                break;
+            }
+            debug!("Warmup pass {} with {}", pass, file.name);
+            let before = Instant::now();
+            inference(model, &file.samples);
+            pass_latencies.push(before.elapsed());
+            latencies.push(before.elapsed());
+        }
+        let mean = LatencySummary::new(&pass_latencies).mean;
+        info!("Warmup pass {}: {} clip(s), mean latency {:.1}ms", pass, pass_latencies.len(), mean);
+
+        status.pass_means_ms.push(mean);
+        status.inferences = latencies.len();
+        status.elapsed_ms = millis(started.elapsed());
+        status.latency_ms = LatencySummary::new(&latencies);
+        tracker.update(status.clone());
+    }
+
+    status.done = true;
+    status.elapsed_ms = millis(started.elapsed());
+    info!(
+        "Warmup of {} done in {:.1}s: {} inference(s) over {} clip(s), {} skipped, \
+         mean latency {:.1}ms on the first pass and {:.1}ms on the last",
+        worker,
+        status.elapsed_ms / 1000.0,
+        status.inferences,
+        status.files,
+        status.skipped,
+        status.pass_means_ms.first().cloned().unwrap_or(0.0),
+        status.pass_means_ms.last().cloned().unwrap_or(0.0)
+    );
+    tracker.update(status);
+}
+
+#[test]
+fn test_warmup_discovery() {
+    let dir = ::std::env::temp_dir().join(format!("ds-srv-warmup-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("nested/deeper")).unwrap();
+    for name in &["b.WAV", "notes.txt", "nested/deeper/c.wav"] {
+        File::create(dir.join(name)).unwrap();
+    }
+    fs::write(dir.join("nested/a.raw"), [0u8, 1, 2, 3]).unwrap();
+    fs::write(dir.join("odd.pcm"), [0u8, 1, 2]).unwrap();
+    // A cycle, which must not be followed
+    ::std::os::unix::fs::symlink(&dir, dir.join("nested/loop")).unwrap();
+
+    assert_eq!(
+        discover(&dir).unwrap(),
+        vec![
+            dir.join("b.WAV"),
+            dir.join("nested/a.raw"),
+            dir.join("nested/deeper/c.wav"),
+            dir.join("odd.pcm"),
+        ]
+    );
+    // Empty WAVs and odd-sized raw PCM are skipped
+    let (audio, skipped) = load(&dir).unwrap();
+    assert_eq!(audio.len(), 1);
+    assert_eq!(audio[0].name, dir.join("nested/a.raw").to_string_lossy());
+    assert_eq!(audio[0].samples.len(), 2);
+    assert_eq!(skipped, 3);
+    assert!(discover(&dir.join("missing")).is_err());
+
+    let tracker = WarmupTracker::new();
+    assert!(tracker.is_done());
+    tracker.register("worker");
+    assert!(!tracker.is_done());
+    tracker.update(WarmupStatus {
+        worker: "worker".to_string(),
+        done: true,
+        ..WarmupStatus::default()
+    });
+    assert!(tracker.is_done());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_synthetic_warmup() {
+    let audio = synthetic(Duration::from_secs(5));
+    let names: Vec<&str> = audio.iter().map(|a| a.name.as_str()).collect();
+    assert_eq!(
+        names,
+        vec!["silence 0.5s", "noise 0.5s", "sweep 0.5s", "silence 1s", "noise 1s", "sweep 1s"]
+    );
+    assert_eq!(audio[3].samples.len(), AUDIO_SAMPLE_RATE as usize);
+    assert!(audio[3].samples.iter().all(|s| *s == 0));
+    assert!(audio[4].samples.iter().any(|s| *s != 0));
+    let loudest = audio[5].samples.iter().map(|s| s.abs()).max().unwrap();
+    assert!(loudest > 9000 && loudest < 10000);
+    assert!(synthetic(Duration::from_millis(400)).is_empty());
+}